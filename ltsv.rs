@@ -27,13 +27,19 @@ fn main() {
 #[license = "MIT license"];
 #[crate_type = "lib"];
 
+extern mod std;
+
 use core::container::Map;
-use core::hashmap::linear::LinearMap;
-use core::io::WriterUtil;
+use core::from_str::FromStr;
+use core::hashmap::linear::{LinearMap, LinearSet};
+use core::io::{ReaderUtil, WriterUtil};
+use std::bitv::Bitv;
+use std::deque::Deque;
+use std::{flate, json, sha1, sort, tempfile, time, timer, uv};
 
 pub type Record = LinearMap<~str, ~str>;
 
-enum ParseType {
+pub enum ParseType {
     FieldLabel,
     FieldValue,
     Field,
@@ -42,24 +48,286 @@ enum ParseType {
 }
 
 #[deriving(Eq)]
-enum ParseDelimiter {
+pub enum ParseDelimiter {
     EOF, TAB, NL, MISC
 }
 
-enum ParseResult<T> {
+pub enum ParseResult<T> {
     ParseError(~str),
     ParseOk(ParseType, ParseDelimiter, T)
 }
 
+pub enum MergePolicy {
+    KeepFirst,
+    KeepLast
+}
+
+pub struct ParseOptions {
+    /// When set, this byte at the start of a field (i.e. right after a TAB)
+    /// turns the rest of the line into a comment. Occurrences inside a
+    /// value are kept as is.
+    inline_comment: Option<u8>,
+    /// When set to `(start, end)`, a value equal to `start` at the end of a
+    /// line is replaced by the following lines, joined with LF, up to a
+    /// line equal to `end`.
+    block_value_markers: Option<(~str, ~str)>,
+    /// Accept fields with an empty value.
+    allow_empty_values: bool,
+    /// Restrict labels to `[0-9A-Za-z_.-]`. Otherwise any byte that may
+    /// appear in a value, except `:`, is accepted.
+    strict_labels: bool,
+    /// What to do when a label appears twice within a record.
+    duplicates: DuplicatePolicy,
+    /// Skip whitespace before each field and after each record.
+    skip_whitespace: bool,
+    /// Read each value as `<len>:<bytes>`: a decimal byte count, a colon,
+    /// then exactly that many bytes, which may include TABs and newlines.
+    length_prefixed_values: bool,
+    /// Labels whose values are percent-decoded, as written by
+    /// `write_ltsv_encoded`.
+    percent_decode_labels: ~[~str],
+    /// Treat the stream ending where the next label of a record should start
+    /// as the end of that record. A partial label at EOF is still an error.
+    tolerate_eof_in_label: bool,
+    /// Callbacks run on the value of each matching label as it is parsed.
+    /// The returned value is stored in its place; an error fails the parse.
+    field_hooks: ~[(~str, @fn(&str) -> Result<~str, ~str>)],
+    /// Limits checked as bytes are read, so that no label, value or line
+    /// longer than allowed is ever held in memory.
+    limits: ParseLimits
+}
+
+pub impl ParseOptions {
+    fn new() -> ParseOptions {
+        ParseOptions {
+            inline_comment: None,
+            block_value_markers: None,
+            allow_empty_values: true,
+            strict_labels: true,
+            duplicates: LastWins,
+            skip_whitespace: true,
+            length_prefixed_values: false,
+            percent_decode_labels: ~[],
+            tolerate_eof_in_label: false,
+            field_hooks: ~[],
+            limits: ParseLimits::unlimited()
+        }
+    }
+}
+
+pub enum DuplicatePolicy {
+    FirstWins,
+    LastWins,
+    RejectDuplicates
+}
+
+pub enum StrictnessProfile {
+    /// Like `Standard`, but labels may contain any byte allowed in a value
+    /// except `:`.
+    Lenient,
+    /// `ParseOptions::new()`: labels limited to `[0-9A-Za-z_.-]`, empty
+    /// values accepted, the last of duplicate labels wins, and whitespace
+    /// around fields and records skipped.
+    Standard,
+    /// Like `Standard`, but empty values and duplicate labels are errors
+    /// and no whitespace is skipped, so blank lines are rejected as well.
+    Strict
+}
+
+pub impl StrictnessProfile {
+    fn options(&self) -> ParseOptions {
+        match *self {
+            Lenient => ParseOptions { strict_labels: false, ..ParseOptions::new() },
+            Standard => ParseOptions::new(),
+            Strict => ParseOptions {
+                allow_empty_values: false,
+                duplicates: RejectDuplicates,
+                skip_whitespace: false,
+                ..ParseOptions::new()
+            }
+        }
+    }
+}
+
+pub struct NumericStats {
+    count: uint,
+    missing: uint,
+    invalid: uint,
+    min: f64,
+    max: f64,
+    sum: f64,
+    mean: f64
+}
+
+#[deriving(Eq)]
+pub enum ValueType {
+    IntType,
+    FloatType,
+    BoolType,
+    StringType
+}
+
+pub struct FieldSchema {
+    label: ~str,
+    always_present: bool,
+    value_type: ValueType,
+    examples: ~[~str]
+}
+
+pub struct ParseLimits {
+    max_label_len: uint,
+    max_value_len: uint,
+    max_line_len: uint,
+    max_fields: uint,
+    max_total_bytes: uint
+}
+
+pub impl ParseLimits {
+    fn unlimited() -> ParseLimits {
+        ParseLimits {
+            max_label_len: uint::max_value,
+            max_value_len: uint::max_value,
+            max_line_len: uint::max_value,
+            max_fields: uint::max_value,
+            max_total_bytes: uint::max_value
+        }
+    }
+}
+
+#[deriving(Eq)]
+pub enum DiffOp {
+    Insert(Record),
+    Delete(Record),
+    Replace(Record, Record)
+}
+
+#[deriving(Eq)]
+pub enum FieldIssue {
+    NonAscii,
+    ControlChar,
+    EmptyValue
+}
+
+pub struct LabelSpec {
+    label: ~str,
+    required: bool,
+    value_type: ValueType,
+    /// If non-empty, the only values the label may take.
+    allowed: ~[~str]
+}
+
+pub struct LtsvSpec {
+    labels: ~[LabelSpec],
+    /// Whether labels not described in `labels` are accepted.
+    allow_unknown: bool
+}
+
+#[deriving(Eq)]
+pub struct SpecViolation {
+    index: uint,
+    reason: ~str
+}
+
+pub struct ParseBenchResult {
+    records: uint,
+    min_ns: f64,
+    max_ns: f64,
+    mean_ns: f64
+}
+
 pub trait LTSVWriter {
     fn write_ltsv(&self, ltsv: &[Record]);
+    /// Writes `record` as one line without the trailing newline. Fails the
+    /// task if a label or value cannot be written as LTSV; use
+    /// `try_write_ltsv_record` to get the error instead.
     fn write_ltsv_record(&self, record: &Record);
+    /// Like `write_ltsv_record`, but returns an error, writing nothing, for
+    /// a record that cannot be written as LTSV.
+    fn try_write_ltsv_record(&self, record: &Record) -> Result<(), ~str>;
+    fn write_ltsv_record_with_cmp(&self, record: &Record,
+                                  cmp: &fn(&str, &str) -> core::cmp::Ordering);
 }
 
 pub trait LTSVReader {
     fn read_ltsv(&self) -> ~[Record];
     fn each_ltsv_record(&self, f: &fn(&Record) -> bool);
     fn each_ltsv_field(&self, f: &fn(&(~str, ~str)) -> bool);
+    fn check_monotonic(&self, label: &str, strictly: bool) -> Result<(), ~str>;
+    fn coalesce_by_key(&self, key_label: &str, policy: MergePolicy) -> Result<~[Record], ~str>;
+    /// Groups records by `ts / bucket_secs`. Records with a missing or
+    /// invalid timestamp go to the `sentinel` bucket if given, and are an
+    /// error otherwise. The sentinel must be a key no real bucket can take,
+    /// e.g. `u64::max_value` when `bucket_secs > 1`; a timestamp that falls
+    /// into it is an error, so the two never mix.
+    fn bucket_by_time(&self, ts_label: &str, bucket_secs: u64, sentinel: Option<u64>)
+                      -> Result<LinearMap<u64, ~[Record]>, ~str>;
+    fn union_labels_ordered(&self) -> Result<~[~str], ~str>;
+    fn read_ltsv_repaired(&self) -> Result<(~[Record], ~[~str]), ~str>;
+    fn verify_ltsv_hash_chain(&self, hash_label: &str) -> Result<(), ~str>;
+    fn each_ltsv_record_validate_field(&self, label: &str, validator: &fn(&str) -> bool,
+                                       f: &fn(Result<&Record, ~str>) -> bool);
+    fn numeric_stats(&self, label: &str) -> Result<NumericStats, ~str>;
+    fn infer_schema(&self, sample_limit: uint) -> Result<~[FieldSchema], ~str>;
+    fn read_ltsv_spilling(&self, mem_limit: uint) -> Result<SpilledRecords, ~str>;
+    fn distinct_values(&self, label: &str) -> Result<~[~str], ~str>;
+    /// Estimates the `p` quantile of a numeric label in constant memory with
+    /// the P² algorithm, ignoring missing and non-numeric values. On smooth
+    /// distributions the estimate is usually within a few percent of the
+    /// exact quantile; heavily skewed or clustered data can be off further.
+    /// With fewer than five values the result is exact.
+    fn percentile_estimate(&self, label: &str, p: f64) -> Result<f64, ~str>;
+    fn find_unexpected_labels(&self, allowed: &[~str]) -> Result<~[(uint, ~str)], ~str>;
+    fn aggregate_by_key(&self, key_label: &str, value_label: &str)
+                        -> Result<LinearMap<~str, (uint, f64)>, ~str>;
+    fn group_by_two(&self, key1: &str, key2: &str)
+                    -> Result<LinearMap<~str, LinearMap<~str, ~[Record]>>, ~str>;
+    fn validate_bounded(&self, limits: ParseLimits) -> Result<uint, ~str>;
+    fn build_bloom(&self, label: &str, bits: uint, hashes: uint) -> Result<BloomFilter, ~str>;
+    fn each_ltsv_record_constrained(&self, constraints: &[&fn(&Record) -> Option<~str>],
+                                    f: &fn(Result<&Record, ~str>) -> bool);
+    fn audit_fields(&self) -> Result<~[(uint, ~str, FieldIssue)], ~str>;
+    fn reservoir_sample(&self, k: uint, seed: u64) -> Result<~[Record], ~str>;
+    fn validate_against_spec(&self, spec: &LtsvSpec) -> Result<(), ~[SpecViolation]>;
+    /// Reads the input into memory once, then parses the buffer `warmup`
+    /// times untimed and `iters` times timed. Times are per record, taken
+    /// over the timed iterations. Parsing stops at the first error, so only
+    /// the records before it are measured.
+    fn bench_parse(&self, warmup: uint, iters: uint) -> ParseBenchResult;
+    fn check_unique(&self, key_label: &str, skip_missing: bool) -> Result<(), ~str>;
+    /// Calls `f` after each record with the record's timestamp and the mean
+    /// of `value_label` over the records whose timestamp falls within the
+    /// last `window_secs` seconds, ending at and including that timestamp.
+    /// Records must be in timestamp order; a timestamp going backwards, or
+    /// a missing or non-numeric timestamp or value, is an error.
+    fn rolling_aggregate(&self, ts_label: &str, value_label: &str, window_secs: u64,
+                         f: &fn(window_end: u64, mean: f64) -> bool) -> Result<(), ~str>;
+    fn top_k_values(&self, label: &str, k: uint) -> Result<~[(~str, uint)], ~str>;
+    fn check_mutually_exclusive(&self, groups: &[~[~str]]) -> Result<(), ~str>;
+    /// Checks the checksum records written by
+    /// `write_ltsv_with_periodic_checksum`. On success, returns how many data
+    /// records are covered by a checksum; records after the last one are not
+    /// verified. On a mismatch, the error names the range of data records,
+    /// counted from 0, in which the corruption lies.
+    fn verify_periodic_checksum(&self, label: &str) -> Result<uint, ~str>;
+    fn pivot(&self, row_key: &str, col_label: &str, val_label: &str) -> Result<~[Record], ~str>;
+    /// Like `each_ltsv_record`, but each label in `defaults` that a record
+    /// lacks is filled in, in order, with its closure's result on the record
+    /// as built so far, so a default may depend on earlier defaults.
+    fn each_ltsv_record_with_lazy_defaults(&self, defaults: &[(~str, @fn(&Record) -> ~str)],
+                                           f: &fn(&Record) -> bool);
+    /// Renders the distribution of a numeric label as one line per bucket of
+    /// equal width between the smallest and largest value, with the fullest
+    /// bucket's bar `width` characters long. Missing and non-numeric values
+    /// are ignored.
+    fn histogram_text(&self, label: &str, buckets: uint, width: uint) -> Result<~str, ~str>;
+    fn check_label_cardinality(&self, max_distinct: uint) -> Result<uint, ~str>;
+    fn each_ltsv_run_by(&self, key_label: &str, f: &fn(&[Record]) -> bool) -> Result<(), ~str>;
+    fn check_numeric_range(&self, label: &str, min: f64, max: f64) -> Result<(), ~str>;
+    fn completeness_report(&self) -> Result<LinearMap<~str, f64>, ~str>;
+    fn max_field_widths(&self) -> Result<LinearMap<~str, uint>, ~str>;
+    fn cooccurrence(&self, label1: &str, label2: &str)
+                    -> Result<LinearMap<(~str, ~str), uint>, ~str>;
+    fn find_corrupted_records(&self, hash_label: &str) -> Result<~[(uint, Record)], ~str>;
 }
 
 impl<T: io::Writer> LTSVWriter for T {
@@ -70,6 +338,10 @@ impl<T: io::Writer> LTSVWriter for T {
         }
     }
     fn write_ltsv_record(&self, record: &Record) {
+        match check_record(record) {
+            Err(reason) => fail!(reason),
+            Ok(()) => ()
+        }
         let mut is_first = true;
         for record.each |&(k, v)| {
             if !is_first { self.write_char('\t'); }
@@ -77,6 +349,27 @@ impl<T: io::Writer> LTSVWriter for T {
             if is_first { is_first = false; }
         }
     }
+    fn try_write_ltsv_record(&self, record: &Record) -> Result<(), ~str> {
+        match check_record(record) {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+        self.write_ltsv_record(record);
+        Ok(())
+    }
+    fn write_ltsv_record_with_cmp(&self, record: &Record,
+                                  cmp: &fn(&str, &str) -> core::cmp::Ordering) {
+        match check_record(record) {
+            Err(reason) => fail!(reason),
+            Ok(()) => ()
+        }
+        let labels = sorted_labels(record);
+        let labels = sort::merge_sort(labels, |a, b| cmp(*a, *b) != core::cmp::Greater);
+        let fields = do labels.map |label| {
+            fmt!("%s:%s", *label, *record.get(label))
+        };
+        self.write_str(str::connect(fields, "\t"));
+    }
 }
 
 impl<T: io::Reader> LTSVReader for T {
@@ -105,23 +398,868 @@ impl<T: io::Reader> LTSVReader for T {
             }
         }
     }
+    fn check_monotonic(&self, label: &str, strictly: bool) -> Result<(), ~str> {
+        let mut prev = None;
+        do LTSVParser::new(self).try_each_record |i, record| {
+            match numeric_value(&record, label, i) {
+                Err(reason) => Err(reason),
+                Ok(v) => match prev {
+                    Some(p) if v < p || (strictly && v == p) => {
+                        Err(fmt!("record %u: %s is not monotonic", i, label))
+                    }
+                    _ => { prev = Some(v); Ok(()) }
+                }
+            }
+        }
+    }
+    fn coalesce_by_key(&self, key_label: &str, policy: MergePolicy) -> Result<~[Record], ~str> {
+        let key = key_label.to_owned();
+        let mut index = LinearMap::new();
+        let mut records: ~[Record] = ~[];
+        let rv = do LTSVParser::new(self).try_each_record |i, record| {
+            match record.find(&key).map(|v| copy **v) {
+                None => Err(missing_label(key_label, i)),
+                Some(k) => {
+                    match index.find(&k) {
+                        Some(&n) => {
+                            let merged = &mut records[n];
+                            for record.each |&(label, value)| {
+                                match policy {
+                                    KeepFirst if merged.contains_key(label) => (),
+                                    _ => { merged.insert(copy *label, copy *value); }
+                                }
+                            }
+                        }
+                        None => {
+                            index.insert(k, records.len());
+                            records.push(record);
+                        }
+                    }
+                    Ok(())
+                }
+            }
+        };
+        with_value(rv, records)
+    }
+    fn bucket_by_time(&self, ts_label: &str, bucket_secs: u64, sentinel: Option<u64>)
+                      -> Result<LinearMap<u64, ~[Record]>, ~str> {
+        if bucket_secs == 0 {
+            return Err(~"bucket_secs must be positive");
+        }
+        let key = ts_label.to_owned();
+        let mut buckets = LinearMap::new();
+        let rv = do LTSVParser::new(self).try_each_record |i, record| {
+            let ts: Option<u64> = match record.find(&key) {
+                Some(value) => from_str::from_str(*value),
+                None => None
+            };
+            let bucket = match (ts, sentinel) {
+                (Some(ts), Some(bucket)) if ts / bucket_secs == bucket => {
+                    Err(fmt!("record %u: %s falls into the sentinel bucket", i, ts_label))
+                }
+                (Some(ts), _) => Ok(ts / bucket_secs),
+                (None, Some(bucket)) => Ok(bucket),
+                (None, None) => Err(fmt!("record %u: missing or invalid %s", i, ts_label))
+            };
+            match bucket {
+                Ok(bucket) => {
+                    if !buckets.contains_key(&bucket) { buckets.insert(bucket, ~[]); }
+                    buckets.find_mut(&bucket).unwrap().push(record);
+                    Ok(())
+                }
+                Err(reason) => Err(reason)
+            }
+        };
+        with_value(rv, buckets)
+    }
+    fn union_labels_ordered(&self) -> Result<~[~str], ~str> {
+        let parser = LTSVParser::new(self);
+        let mut seen = LinearSet::new();
+        let mut labels = ~[];
+        while !parser.eof() {
+            match parser.parse_field() {
+                ParseError(reason) => return Err(reason),
+                ParseOk(_, _, (label, _)) => {
+                    if !seen.contains(&label) {
+                        seen.insert(copy label);
+                        labels.push(label);
+                    }
+                }
+            }
+        }
+        Ok(labels)
+    }
+    fn read_ltsv_repaired(&self) -> Result<(~[Record], ~[~str]), ~str> {
+        let lines = vec::split(self.read_whole_stream(), |b| *b == 0x0a);
+        let mut records = ~[];
+        let mut repairs = ~[];
+        for lines.eachi |i, line| {
+            let lineno = i + 1;
+            let mut line = copy *line;
+            if line.len() > 0 && line[line.len() - 1] == 0x0d {
+                line.pop();
+                repairs.push(fmt!("line %u: normalized CRLF", lineno));
+            }
+            if line.all(|b| char::is_whitespace(*b as char)) {
+                // the empty string after the final LF is not a line.
+                if lineno < lines.len() || line.len() > 0 {
+                    repairs.push(fmt!("line %u: dropped empty line", lineno));
+                }
+                loop;
+            }
+            let mut collapsed = ~[];
+            for line.each |&b| {
+                if b != 0x09 || collapsed.last_opt() != Some(&0x09) {
+                    collapsed.push(b);
+                }
+            }
+            if collapsed.len() < line.len() {
+                repairs.push(fmt!("line %u: collapsed doubled tabs", lineno));
+            }
+            if collapsed.last_opt() == Some(&0x09) {
+                collapsed.pop();
+                repairs.push(fmt!("line %u: stripped trailing tabs", lineno));
+            }
+            match io::with_bytes_reader(collapsed, |rd| LTSVParser::new(rd).parse_record()) {
+                ParseError(reason) => return Err(fmt!("line %u: %s", lineno, reason)),
+                ParseOk(_, _, record) => records.push(record)
+            }
+        }
+        Ok((records, repairs))
+    }
+    fn verify_ltsv_hash_chain(&self, hash_label: &str) -> Result<(), ~str> {
+        let key = hash_label.to_owned();
+        let mut prev = ~"";
+        do LTSVParser::new(self).try_each_record |i, record| {
+            let mut record = record;
+            match record.pop(&key) {
+                None => Err(missing_label(hash_label, i)),
+                Some(stored) => {
+                    if chain_hash(prev, &record) == stored {
+                        prev = stored;
+                        Ok(())
+                    } else {
+                        Err(fmt!("record %u: hash chain broken", i))
+                    }
+                }
+            }
+        }
+    }
+    fn each_ltsv_record_validate_field(&self, label: &str, validator: &fn(&str) -> bool,
+                                       f: &fn(Result<&Record, ~str>) -> bool) {
+        let key = label.to_owned();
+        let parser = LTSVParser::new(self);
+        let mut index = 0u;
+        while !parser.eof() {
+            match parser.parse_record() {
+                ParseError(reason) => { f(Err(reason)); break; }
+                ParseOk(_, _, record) => {
+                    let rv = match record.find(&key) {
+                        Some(value) if validator(*value) => Ok(&record),
+                        Some(value) => Err(fmt!("record %u: invalid %s: %s", index, label, *value)),
+                        None => Err(missing_label(label, index))
+                    };
+                    if !f(rv) { break; }
+                }
+            }
+            index += 1;
+        }
+    }
+    fn numeric_stats(&self, label: &str) -> Result<NumericStats, ~str> {
+        let key = label.to_owned();
+        let mut stats = NumericStats {
+            count: 0, missing: 0, invalid: 0, min: 0.0, max: 0.0, sum: 0.0, mean: 0.0
+        };
+        let rv = do LTSVParser::new(self).each_record |record| {
+            match record.find(&key) {
+                None => stats.missing += 1,
+                Some(value) => match from_str::from_str::<f64>(*value) {
+                    None => stats.invalid += 1,
+                    Some(v) => {
+                        if stats.count == 0 || v < stats.min { stats.min = v; }
+                        if stats.count == 0 || v > stats.max { stats.max = v; }
+                        stats.sum += v;
+                        stats.count += 1;
+                    }
+                }
+            }
+            true
+        };
+        if stats.count > 0 {
+            stats.mean = stats.sum / (stats.count as f64);
+        }
+        with_value(rv, stats)
+    }
+    fn infer_schema(&self, sample_limit: uint) -> Result<~[FieldSchema], ~str> {
+        let mut fields: LinearMap<~str, FieldSchema> = LinearMap::new();
+        let mut counts: LinearMap<~str, uint> = LinearMap::new();
+        let mut sampled = 0u;
+        let rv = do LTSVParser::new(self).each_record |record| {
+            if sampled < sample_limit {
+                for record.each |&(label, value)| {
+                    let ty = detect_type(*value);
+                    if !fields.contains_key(label) {
+                        fields.insert(copy *label, FieldSchema {
+                            label: copy *label,
+                            always_present: false,
+                            value_type: ty,
+                            examples: ~[]
+                        });
+                        counts.insert(copy *label, 0);
+                    }
+                    let field = fields.find_mut(label).unwrap();
+                    field.value_type = unify_types(field.value_type, ty);
+                    if field.examples.len() < 3 && !field.examples.contains(value) {
+                        field.examples.push(copy *value);
+                    }
+                    *counts.find_mut(label).unwrap() += 1;
+                }
+                sampled += 1;
+            }
+            sampled < sample_limit
+        };
+        match rv {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+        let mut schema = ~[];
+        for fields.each_value |field| {
+            let mut field = copy *field;
+            field.always_present = *counts.get(&field.label) == sampled;
+            schema.push(field);
+        }
+        Ok(sort::merge_sort(schema, |a, b| a.label <= b.label))
+    }
+    fn read_ltsv_spilling(&self, mem_limit: uint) -> Result<SpilledRecords, ~str> {
+        let mut records = SpilledRecords { dir: None, wr: None, nspilled: 0, recent: ~[] };
+        let rv = do LTSVParser::new(self).try_each_record |_, record| {
+            records.recent.push(record);
+            if records.recent.len() > mem_limit { records.spill() } else { Ok(()) }
+        };
+        // close the spill file so that `each` can read it back.
+        records.wr = None;
+        with_value(rv, records)
+    }
+    fn distinct_values(&self, label: &str) -> Result<~[~str], ~str> {
+        let key = label.to_owned();
+        let mut seen = LinearSet::new();
+        let rv = do LTSVParser::new(self).each_record |record| {
+            match record.find(&key) {
+                Some(value) => { seen.insert(copy *value); }
+                None => ()
+            }
+            true
+        };
+        match rv {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+        let mut values = ~[];
+        for seen.each |value| {
+            values.push(copy *value);
+        }
+        sort::tim_sort(values);
+        Ok(values)
+    }
+    fn percentile_estimate(&self, label: &str, p: f64) -> Result<f64, ~str> {
+        if p < 0.0 || p > 1.0 {
+            return Err(~"percentile must be within [0, 1]");
+        }
+        let key = label.to_owned();
+        let mut estimator = P2Estimator::new(p);
+        let rv = do LTSVParser::new(self).each_record |record| {
+            match record.find(&key) {
+                Some(value) => match from_str::from_str(*value) {
+                    Some(v) => estimator.add(v),
+                    None => ()
+                },
+                None => ()
+            }
+            true
+        };
+        match rv {
+            Err(reason) => Err(reason),
+            Ok(()) => match estimator.estimate() {
+                Some(v) => Ok(v),
+                None => Err(fmt!("no numeric values for %s", label))
+            }
+        }
+    }
+    fn find_unexpected_labels(&self, allowed: &[~str]) -> Result<~[(uint, ~str)], ~str> {
+        let mut unexpected = ~[];
+        let rv = do LTSVParser::new(self).try_each_record |i, record| {
+            for sorted_labels(&record).each |label| {
+                if !allowed.contains(label) {
+                    unexpected.push((i, copy *label));
+                }
+            }
+            Ok(())
+        };
+        with_value(rv, unexpected)
+    }
+    fn aggregate_by_key(&self, key_label: &str, value_label: &str)
+                        -> Result<LinearMap<~str, (uint, f64)>, ~str> {
+        let key = key_label.to_owned();
+        let mut aggregates = LinearMap::new();
+        let rv = do LTSVParser::new(self).try_each_record |i, record| {
+            match (record.find(&key), numeric_value(&record, value_label, i)) {
+                (None, _) => Err(missing_label(key_label, i)),
+                (_, Err(reason)) => Err(reason),
+                (Some(k), Ok(v)) => {
+                    let (count, sum) = match aggregates.find(k) {
+                        Some(&(count, sum)) => (count, sum),
+                        None => (0, 0.0)
+                    };
+                    aggregates.insert(copy *k, (count + 1, sum + v));
+                    Ok(())
+                }
+            }
+        };
+        with_value(rv, aggregates)
+    }
+    fn group_by_two(&self, key1: &str, key2: &str)
+                    -> Result<LinearMap<~str, LinearMap<~str, ~[Record]>>, ~str> {
+        let (k1, k2) = (key1.to_owned(), key2.to_owned());
+        let mut groups = LinearMap::new();
+        let rv = do LTSVParser::new(self).try_each_record |i, record| {
+            match (record.find(&k1).map(|v| copy **v), record.find(&k2).map(|v| copy **v)) {
+                (None, _) => Err(missing_label(key1, i)),
+                (_, None) => Err(missing_label(key2, i)),
+                (Some(v1), Some(v2)) => {
+                    if !groups.contains_key(&v1) {
+                        groups.insert(copy v1, LinearMap::new());
+                    }
+                    let inner = groups.find_mut(&v1).unwrap();
+                    if !inner.contains_key(&v2) {
+                        inner.insert(copy v2, ~[]);
+                    }
+                    inner.find_mut(&v2).unwrap().push(record);
+                    Ok(())
+                }
+            }
+        };
+        with_value(rv, groups)
+    }
+    fn validate_bounded(&self, limits: ParseLimits) -> Result<uint, ~str> {
+        let opts = ParseOptions { limits: limits, ..ParseOptions::new() };
+        let parser = LTSVParser::with_options(self, opts);
+        let mut nrecords = 0u;
+        while !parser.eof() {
+            match parser.parse_record() {
+                ParseError(reason) => return Err(fmt!("record %u: %s", nrecords, reason)),
+                ParseOk(_, _, _) => nrecords += 1
+            }
+        }
+        Ok(nrecords)
+    }
+    fn build_bloom(&self, label: &str, bits: uint, hashes: uint) -> Result<BloomFilter, ~str> {
+        let key = label.to_owned();
+        let mut bloom = match BloomFilter::new(bits, hashes) {
+            Ok(bloom) => bloom,
+            Err(reason) => return Err(reason)
+        };
+        let rv = do LTSVParser::new(self).each_record |record| {
+            match record.find(&key) {
+                Some(value) => bloom.insert(*value),
+                None => ()
+            }
+            true
+        };
+        with_value(rv, bloom)
+    }
+    fn each_ltsv_record_constrained(&self, constraints: &[&fn(&Record) -> Option<~str>],
+                                    f: &fn(Result<&Record, ~str>) -> bool) {
+        let parser = LTSVParser::new(self);
+        let mut index = 0u;
+        while !parser.eof() {
+            match parser.parse_record() {
+                ParseError(reason) => { f(Err(reason)); break; }
+                ParseOk(_, _, record) => {
+                    let mut violation = None;
+                    for constraints.each |constraint| {
+                        violation = (*constraint)(&record);
+                        if violation.is_some() { break; }
+                    }
+                    let rv = match violation {
+                        Some(reason) => Err(fmt!("record %u: %s", index, reason)),
+                        None => Ok(&record)
+                    };
+                    if !f(rv) { break; }
+                }
+            }
+            index += 1;
+        }
+    }
+    fn audit_fields(&self) -> Result<~[(uint, ~str, FieldIssue)], ~str> {
+        let mut issues = ~[];
+        let rv = do LTSVParser::new(self).try_each_record |i, record| {
+            for sorted_labels(&record).each |label| {
+                let value = record.get(label);
+                if value.is_empty() {
+                    issues.push((i, copy *label, EmptyValue));
+                }
+                if !str::is_ascii(*value) {
+                    issues.push((i, copy *label, NonAscii));
+                }
+                if str::any(*value, |c| c < ' ' || c == '\x7f') {
+                    issues.push((i, copy *label, ControlChar));
+                }
+            }
+            Ok(())
+        };
+        with_value(rv, issues)
+    }
+    fn reservoir_sample(&self, k: uint, seed: u64) -> Result<~[Record], ~str> {
+        let mut rng = Rng64::new(seed);
+        let mut sample = ~[];
+        let mut seen = 0u;
+        let rv = do LTSVParser::new(self).each_record |record| {
+            if sample.len() < k {
+                sample.push(record);
+            } else {
+                let j = rng.next_below(seen + 1);
+                if j < k { sample[j] = record; }
+            }
+            seen += 1;
+            true
+        };
+        with_value(rv, sample)
+    }
+    fn validate_against_spec(&self, spec: &LtsvSpec) -> Result<(), ~[SpecViolation]> {
+        let mut violations = ~[];
+        let mut index = 0u;
+        let rv = do LTSVParser::new(self).each_record |record| {
+            for spec.labels.each |ls| {
+                match record.find(&ls.label) {
+                    None => if ls.required {
+                        violations.push(SpecViolation {
+                            index: index,
+                            reason: fmt!("missing label %s", ls.label)
+                        });
+                    },
+                    Some(value) => {
+                        if !value_matches(*value, ls.value_type) {
+                            violations.push(SpecViolation {
+                                index: index,
+                                reason: fmt!("label %s: %s is not %s",
+                                             ls.label, *value, type_name(ls.value_type))
+                            });
+                        } else if !ls.allowed.is_empty() && !ls.allowed.contains(value) {
+                            violations.push(SpecViolation {
+                                index: index,
+                                reason: fmt!("label %s: %s is not an allowed value",
+                                             ls.label, *value)
+                            });
+                        }
+                    }
+                }
+            }
+            if !spec.allow_unknown {
+                for sorted_labels(&record).each |label| {
+                    if !spec.labels.any(|ls| ls.label == *label) {
+                        violations.push(SpecViolation {
+                            index: index,
+                            reason: fmt!("unknown label %s", *label)
+                        });
+                    }
+                }
+            }
+            index += 1;
+            true
+        };
+        match rv {
+            Err(reason) => violations.push(SpecViolation { index: index, reason: reason }),
+            Ok(()) => ()
+        }
+        if violations.is_empty() { Ok(()) } else { Err(violations) }
+    }
+    fn bench_parse(&self, warmup: uint, iters: uint) -> ParseBenchResult {
+        let bytes = self.read_whole_stream();
+        let parse = || {
+            do io::with_bytes_reader(bytes) |rd| {
+                let mut n = 0u;
+                LTSVParser::new(rd).each_record(|_| { n += 1; true });
+                n
+            }
+        };
+        for warmup.times { parse(); }
+        let mut rv = ParseBenchResult { records: 0, min_ns: 0.0, max_ns: 0.0, mean_ns: 0.0 };
+        let mut total = 0.0;
+        for uint::range(0, iters) |i| {
+            let start = time::precise_time_ns();
+            let n = parse();
+            let elapsed = (time::precise_time_ns() - start) as f64;
+            let per_record = if n == 0 { 0.0 } else { elapsed / (n as f64) };
+            if i == 0 || per_record < rv.min_ns { rv.min_ns = per_record; }
+            if i == 0 || per_record > rv.max_ns { rv.max_ns = per_record; }
+            total += per_record;
+            rv.records = n;
+        }
+        if iters > 0 { rv.mean_ns = total / (iters as f64); }
+        rv
+    }
+    fn check_unique(&self, key_label: &str, skip_missing: bool) -> Result<(), ~str> {
+        let key = key_label.to_owned();
+        let mut seen = LinearMap::new();
+        do LTSVParser::new(self).try_each_record |i, record| {
+            match record.find(&key) {
+                None if skip_missing => Ok(()),
+                None => Err(missing_label(key_label, i)),
+                Some(value) => match seen.find(value) {
+                    Some(&first) => Err(fmt!("record %u: duplicate %s %s (first seen in record %u)",
+                                             i, key_label, *value, first)),
+                    None => { seen.insert(copy *value, i); Ok(()) }
+                }
+            }
+        }
+    }
+    fn rolling_aggregate(&self, ts_label: &str, value_label: &str, window_secs: u64,
+                         f: &fn(window_end: u64, mean: f64) -> bool) -> Result<(), ~str> {
+        if window_secs == 0 {
+            return Err(~"window_secs must be positive");
+        }
+        let key = ts_label.to_owned();
+        let parser = LTSVParser::new(self);
+        let mut window = Deque::new();
+        let mut sum = 0.0;
+        let mut index = 0u;
+        while !parser.eof() {
+            let record = match parser.parse_record() {
+                ParseError(reason) => return Err(reason),
+                ParseOk(_, _, record) => record
+            };
+            let ts: Option<u64> = match record.find(&key) {
+                Some(value) => from_str::from_str(*value),
+                None => None
+            };
+            let ts = match ts {
+                Some(ts) => ts,
+                None => return Err(fmt!("record %u: missing or invalid %s", index, ts_label))
+            };
+            let value = match numeric_value(&record, value_label, index) {
+                Ok(value) => value,
+                Err(reason) => return Err(reason)
+            };
+            if window.len() > 0 {
+                let &(last, _) = window.peek_back();
+                if ts < last {
+                    return Err(fmt!("record %u: %s is out of order", index, ts_label));
+                }
+            }
+            window.add_back((ts, value));
+            sum += value;
+            loop {
+                let &(first, _) = window.peek_front();
+                if ts - first < window_secs { break; }
+                let (_, old) = window.pop_front();
+                sum -= old;
+            }
+            if !f(ts, sum / (window.len() as f64)) { break; }
+            index += 1;
+        }
+        Ok(())
+    }
+    fn top_k_values(&self, label: &str, k: uint) -> Result<~[(~str, uint)], ~str> {
+        let key = label.to_owned();
+        let mut counts = LinearMap::new();
+        let rv = do LTSVParser::new(self).each_record |record| {
+            match record.find(&key) {
+                Some(value) => {
+                    let n = match counts.find(value) { Some(&n) => n, None => 0u };
+                    counts.insert(copy *value, n + 1);
+                }
+                None => ()
+            }
+            true
+        };
+        match rv {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+        let mut top = ~[];
+        for counts.each |&(value, n)| {
+            top.push((copy *value, *n));
+        }
+        let mut top = do sort::merge_sort(top) |&(ref a, m), &(ref b, n)| {
+            m > n || (m == n && *a <= *b)
+        };
+        top.truncate(k);
+        Ok(top)
+    }
+    fn check_mutually_exclusive(&self, groups: &[~[~str]]) -> Result<(), ~str> {
+        do LTSVParser::new(self).try_each_record |i, record| {
+            let mut rv = Ok(());
+            for groups.each |group| {
+                let present = group.filtered(|label| record.contains_key(label));
+                if present.len() != 1 {
+                    rv = Err(fmt!("record %u: expected exactly one of %s, found %u",
+                                  i, str::connect(*group, ", "), present.len()));
+                    break;
+                }
+            }
+            rv
+        }
+    }
+    fn verify_periodic_checksum(&self, label: &str) -> Result<uint, ~str> {
+        let key = label.to_owned();
+        let mut h = FNV_OFFSET_BASIS;
+        let mut verified = 0u;
+        let mut n = 0u;
+        let rv = do LTSVParser::new(self).try_each_record |i, record| {
+            match record.find(&key) {
+                Some(_) if record.len() != 1 => {
+                    Err(fmt!("record %u: %s mixed with data", i, label))
+                }
+                Some(sum) if *sum != hex_u64(h) => {
+                    // no data since the last checksum, e.g. one before any data.
+                    if n == verified {
+                        Err(fmt!("record %u: checksum mismatch", i))
+                    } else {
+                        Err(fmt!("records %u-%u: checksum mismatch", verified, n - 1))
+                    }
+                }
+                Some(_) => { verified = n; Ok(()) }
+                None => { h = running_checksum(h, &record); n += 1; Ok(()) }
+            }
+        };
+        with_value(rv, verified)
+    }
+    fn pivot(&self, row_key: &str, col_label: &str, val_label: &str) -> Result<~[Record], ~str> {
+        let (row, col, val) = (row_key.to_owned(), col_label.to_owned(), val_label.to_owned());
+        let mut index = LinearMap::new();
+        let mut rows: ~[Record] = ~[];
+        let rv = do LTSVParser::new(self).try_each_record |i, record| {
+            match (record.find(&row), record.find(&col), record.find(&val)) {
+                (None, _, _) => Err(missing_label(row_key, i)),
+                (_, None, _) => Err(missing_label(col_label, i)),
+                (_, _, None) => Err(missing_label(val_label, i)),
+                (Some(r), Some(c), Some(v)) => {
+                    if !is_valid_label(*c) || *c == row {
+                        Err(fmt!("record %u: %s cannot be used as a label", i, *c))
+                    } else {
+                        let n = match index.find(r) {
+                            Some(&n) => n,
+                            None => {
+                                let mut wide = LinearMap::new();
+                                wide.insert(copy row, copy *r);
+                                rows.push(wide);
+                                rows.len() - 1
+                            }
+                        };
+                        index.insert(copy *r, n);
+                        rows[n].insert(copy *c, copy *v);
+                        Ok(())
+                    }
+                }
+            }
+        };
+        with_value(rv, rows)
+    }
+    fn each_ltsv_record_with_lazy_defaults(&self, defaults: &[(~str, @fn(&Record) -> ~str)],
+                                           f: &fn(&Record) -> bool) {
+        for self.each_ltsv_record |record| {
+            let mut record = copy *record;
+            for defaults.each |&(ref label, default)| {
+                if !record.contains_key(label) {
+                    let value = default(&record);
+                    record.insert(copy *label, value);
+                }
+            }
+            if !f(&record) { break; }
+        }
+    }
+    fn histogram_text(&self, label: &str, buckets: uint, width: uint) -> Result<~str, ~str> {
+        if buckets == 0 {
+            return Err(~"buckets must be positive");
+        }
+        let key = label.to_owned();
+        let mut values = ~[];
+        let rv = do LTSVParser::new(self).each_record |record| {
+            match record.find(&key) {
+                Some(value) => match from_str::from_str::<f64>(*value) {
+                    Some(v) => values.push(v),
+                    None => ()
+                },
+                None => ()
+            }
+            true
+        };
+        match rv {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+        if values.is_empty() {
+            return Ok(~"");
+        }
+        let (min, max, counts) = histogram_counts(values, buckets);
+        let step = (max - min) / (buckets as f64);
+        let highest = counts.foldl(0, |a, b| uint::max(*a, *b));
+        let mut rv = ~"";
+        for counts.eachi |i, &count| {
+            let lo = min + step * (i as f64);
+            let bar = str::from_chars(vec::from_elem(count * width / highest, '#'));
+            str::push_str(&mut rv, fmt!("%s - %s | %s %u\n",
+                                        f64::to_str_digits(lo, 3),
+                                        f64::to_str_digits(lo + step, 3), bar, count));
+        }
+        Ok(rv)
+    }
+    fn check_label_cardinality(&self, max_distinct: uint) -> Result<uint, ~str> {
+        let mut labels = LinearSet::new();
+        let rv = do LTSVParser::new(self).try_each_record |i, record| {
+            for record.each_key |label| {
+                labels.insert(copy *label);
+            }
+            if labels.len() > max_distinct {
+                Err(fmt!("record %u: %u distinct labels exceed the limit of %u",
+                         i, labels.len(), max_distinct))
+            } else {
+                Ok(())
+            }
+        };
+        with_value(rv, labels.len())
+    }
+    fn each_ltsv_run_by(&self, key_label: &str, f: &fn(&[Record]) -> bool) -> Result<(), ~str> {
+        let key = key_label.to_owned();
+        let parser = LTSVParser::new(self);
+        let mut run: ~[Record] = ~[];
+        let mut index = 0u;
+        loop {
+            let record = match parser.next_record() {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(reason) => return Err(reason)
+            };
+            let k = match record.find(&key) {
+                Some(k) => copy *k,
+                None => return Err(missing_label(key_label, index))
+            };
+            let same = match run.last_opt() {
+                Some(last) => *last.get(&key) == k,
+                None => true
+            };
+            if !same {
+                if !f(run) { return Ok(()); }
+                run = ~[];
+            }
+            run.push(record);
+            index += 1;
+        }
+        if !run.is_empty() { f(run); }
+        Ok(())
+    }
+    fn check_numeric_range(&self, label: &str, min: f64, max: f64) -> Result<(), ~str> {
+        do LTSVParser::new(self).try_each_record |i, record| {
+            match numeric_value(&record, label, i) {
+                Ok(v) if v < min || v > max => {
+                    Err(fmt!("record %u: %s is out of range: %s", i, label,
+                             *record.get(&label.to_owned())))
+                }
+                Ok(_) => Ok(()),
+                Err(reason) => Err(reason)
+            }
+        }
+    }
+    fn completeness_report(&self) -> Result<LinearMap<~str, f64>, ~str> {
+        let mut filled: LinearMap<~str, uint> = LinearMap::new();
+        let mut total = 0u;
+        let rv = do LTSVParser::new(self).each_record |record| {
+            for record.each |&(label, value)| {
+                let n = match filled.find(label) { Some(&n) => n, None => 0 };
+                filled.insert(copy *label, if value.is_empty() { n } else { n + 1 });
+            }
+            total += 1;
+            true
+        };
+        match rv {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+        let mut report = LinearMap::new();
+        for filled.each |&(label, n)| {
+            report.insert(copy *label, (*n as f64) / (total as f64));
+        }
+        Ok(report)
+    }
+    fn max_field_widths(&self) -> Result<LinearMap<~str, uint>, ~str> {
+        let mut widths = LinearMap::new();
+        let rv = do LTSVParser::new(self).each_record |record| {
+            for record.each |&(label, value)| {
+                let width = match widths.find(label) { Some(&w) => w, None => 0u };
+                widths.insert(copy *label, uint::max(width, value.len()));
+            }
+            true
+        };
+        with_value(rv, widths)
+    }
+    fn cooccurrence(&self, label1: &str, label2: &str)
+                    -> Result<LinearMap<(~str, ~str), uint>, ~str> {
+        let (key1, key2) = (label1.to_owned(), label2.to_owned());
+        let mut counts = LinearMap::new();
+        let rv = do LTSVParser::new(self).each_record |record| {
+            match (record.find(&key1), record.find(&key2)) {
+                (Some(v1), Some(v2)) => {
+                    let pair = (copy *v1, copy *v2);
+                    let n = match counts.find(&pair) { Some(&n) => n, None => 0u };
+                    counts.insert(pair, n + 1);
+                }
+                _ => ()
+            }
+            true
+        };
+        with_value(rv, counts)
+    }
+    fn find_corrupted_records(&self, hash_label: &str) -> Result<~[(uint, Record)], ~str> {
+        let key = hash_label.to_owned();
+        let mut corrupted = ~[];
+        let rv = do LTSVParser::new(self).try_each_record |i, record| {
+            let mut data = copy record;
+            match data.pop(&key) {
+                None => Err(missing_label(hash_label, i)),
+                Some(stored) => {
+                    if record_hash(&data) != stored {
+                        corrupted.push((i, record));
+                    }
+                    Ok(())
+                }
+            }
+        };
+        with_value(rv, corrupted)
+    }
 }
 
-struct LTSVParser<'self, T> {
+pub struct LTSVParser<'self, T> {
     priv rd: &'self T,
-    priv cur: @mut int
+    priv cur: @mut int,
+    priv pos: @mut uint,
+    priv nfields: @mut uint,
+    priv record_start: @mut uint,
+    priv opts: ParseOptions
 }
 
 pub impl<'self, T: io::Reader> LTSVParser<'self, T> {
     fn new(rd: &'self T) -> LTSVParser<'self, T> {
+        LTSVParser::with_options(rd, ParseOptions::new())
+    }
+
+    fn with_profile(rd: &'self T, profile: StrictnessProfile) -> LTSVParser<'self, T> {
+        LTSVParser::with_options(rd, profile.options())
+    }
+
+    fn with_options(rd: &'self T, opts: ParseOptions) -> LTSVParser<'self, T> {
         let cur = @mut rd.read_byte();
-        LTSVParser { rd: rd, cur: cur }
+        LTSVParser { rd: rd, cur: cur, pos: @mut 0, nfields: @mut 0, record_start: @mut 0,
+                     opts: opts }
     }
 
     fn eof(&self) -> bool {
         *self.cur == -1
     }
 
+    fn pos(&self) -> uint {
+        *self.pos
+    }
+
     fn parse_ltsv(&self) -> ParseResult<~[Record]> {
         let mut records = ~[];
         loop {
@@ -141,40 +1279,153 @@ pub impl<'self, T: io::Reader> LTSVParser<'self, T> {
         ParseOk(Ltsv, EOF, records)
     }
 
-    fn parse_record(&self) -> ParseResult<Record> {
-        let mut record = LinearMap::new();
-        loop {
-            match self.parse_field() {
-                ParseError(reason) => {
-                    return ParseError(reason);
-                }
-                ParseOk(_, TAB, (label, value)) => {
-                    record.insert(label, value);
-                }
-                ParseOk(_, delim, (label, value)) => {
-                    record.insert(label, value);
-                    return ParseOk(Record, delim, record);
-                }
+    fn each_record(&self, f: &fn(Record) -> bool) -> Result<(), ~str> {
+        while !self.eof() {
+            match self.parse_record() {
+                ParseError(reason) => return Err(reason),
+                ParseOk(_, _, record) => if !f(record) { break; }
             }
         }
+        Ok(())
     }
 
-    fn parse_field(&self) -> ParseResult<(~str, ~str)> {
+    fn next_record(&self) -> Result<Option<Record>, ~str> {
+        if self.eof() {
+            return Ok(None);
+        }
+        match self.parse_record() {
+            ParseError(reason) => Err(reason),
+            ParseOk(_, _, record) => Ok(Some(record))
+        }
+    }
+
+    fn read_records(&self) -> Result<~[Record], ~str> {
+        let mut records = ~[];
+        let rv = do self.each_record |record| {
+            records.push(record);
+            true
+        };
+        with_value(rv, records)
+    }
+
+    priv fn try_each_record(&self, f: &fn(uint, Record) -> Result<(), ~str>)
+                            -> Result<(), ~str> {
+        let mut index = 0u;
+        while !self.eof() {
+            match self.parse_record() {
+                ParseError(reason) => return Err(reason),
+                ParseOk(_, _, record) => match f(index, record) {
+                    Ok(()) => index += 1,
+                    Err(reason) => return Err(reason)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn seek_to_label(&self, label: &str) -> Result<Option<~str>, ~str> {
+        while !self.eof() {
+            match self.parse_field() {
+                ParseError(reason) => return Err(reason),
+                ParseOk(_, delim, (field_label, value)) => {
+                    if str::eq_slice(field_label, label) { return Ok(Some(value)); }
+                    if delim != TAB { break; }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn parse_record(&self) -> ParseResult<Record> {
+        let mut record = LinearMap::new();
+        *self.nfields = 0;
+        loop {
+            match self.parse_field() {
+                ParseError(reason) => {
+                    return ParseError(reason);
+                }
+                ParseOk(_, delim, (label, value)) => {
+                    match self.insert_field(&mut record, label, value) {
+                        Some(reason) => return ParseError(reason),
+                        None => ()
+                    }
+                    if delim != TAB {
+                        return ParseOk(Record, delim, record);
+                    }
+                    if self.at_inline_comment() {
+                        let delim = self.skip_inline_comment();
+                        if self.over_budget() { return ParseError(self.budget_error()); }
+                        return ParseOk(Record, delim, record);
+                    }
+                    if self.opts.tolerate_eof_in_label {
+                        self.skip_whitespaces();
+                        if self.over_budget() { return ParseError(self.budget_error()); }
+                        if self.eof() { return ParseOk(Record, EOF, record); }
+                    }
+                }
+            }
+        }
+    }
+
+    fn parse_field(&self) -> ParseResult<(~str, ~str)> {
         self.skip_whitespaces();
+        if self.over_budget() { return ParseError(self.budget_error()); }
+        if *self.nfields == 0 { *self.record_start = *self.pos; }
+        *self.nfields += 1;
+        if *self.nfields > self.opts.limits.max_fields {
+            return ParseError(fmt!("more than %u fields", self.opts.limits.max_fields));
+        }
         let label = match self.parse_field_label() {
             ParseError(reason) => return ParseError(reason),
             ParseOk(_, _, label) => { self.bump(); label }
         };
-        match self.parse_field_value() {
+        let value = if self.opts.length_prefixed_values {
+            self.parse_length_prefixed_value(label)
+        } else {
+            self.parse_field_value(label)
+        };
+        match value {
             ParseError(reason) => {
                 ParseError(reason)
             }
             ParseOk(_, delim, value) => {
+                let end = if delim == NL && !self.opts.length_prefixed_values {
+                    self.block_end(value)
+                } else {
+                    None
+                };
+                let value = match end {
+                    Some(end) => match self.parse_block_value(label, end) {
+                        ParseError(reason) => return ParseError(reason),
+                        ParseOk(_, _, value) => value
+                    },
+                    None => value
+                };
+                let value = if self.opts.percent_decode_labels.contains(&label) {
+                    match percent_decode(value) {
+                        Ok(value) => value,
+                        Err(reason) => return ParseError(reason)
+                    }
+                } else {
+                    value
+                };
+                let value = match self.opts.field_hooks.find(|&(ref l, _)| *l == label) {
+                    Some((_, hook)) => match hook(value) {
+                        Ok(value) => value,
+                        Err(reason) => return ParseError(fmt!("label %s: %s", label, reason))
+                    },
+                    None => value
+                };
+                if value.is_empty() && !self.opts.allow_empty_values {
+                    return ParseError(~"value is empty");
+                }
                 self.bump();
                 // avoid skipping whitespaces in the middle of parsing record.
                 if delim != TAB { self.skip_whitespaces(); }
+                if self.over_budget() { return ParseError(self.budget_error()); }
                 // re-check EOF
                 let delim = if self.eof() { EOF } else { delim };
+                if delim != TAB { *self.nfields = 0; }
                 ParseOk(Field, delim, (label, value))
             }
         }
@@ -184,33 +1435,161 @@ pub impl<'self, T: io::Reader> LTSVParser<'self, T> {
         let mut bytes = ~[];
         loop {
             match *self.cur {
-                0x30..0x39 | 0x41..0x5a | 0x61..0x7a | 0x5f |
-                0x2e | 0x2d => bytes.push(*self.cur as u8),
+                c if is_label_byte(c) => bytes.push(c as u8),
+                c if !self.opts.strict_labels && c != 0x3a &&
+                     is_value_byte(c) => bytes.push(c as u8),
                 0x3a if bytes.len() == 0 => return ParseError(~"label is empty"),
                 0x3a => return ParseOk(FieldLabel, MISC, str::from_bytes(bytes)),
                 -1   => return ParseError(~"EOF while parsing field label"),
                 _    => return ParseError(~"invalid byte detected")
             }
+            if bytes.len() > self.opts.limits.max_label_len {
+                return ParseError(fmt!("label exceeds %u bytes", self.opts.limits.max_label_len));
+            }
+            match self.check_position_limits() {
+                Some(reason) => return ParseError(reason),
+                None => ()
+            }
             self.bump();
         }
     }
 
-    priv fn parse_field_value(&self) -> ParseResult<~str> {
+    priv fn parse_field_value(&self, label: &str) -> ParseResult<~str> {
         let mut bytes = ~[];
         loop {
             match *self.cur {
-                0x01..0x08 | 0x0b | 0x0c |
-                0x0e..0xff => bytes.push(*self.cur as u8),
+                c if is_value_byte(c) => bytes.push(c as u8),
                 0x0d => return self.consume_forward_LF(str::from_bytes(bytes)),
                 0x0a => return ParseOk(FieldValue, NL, str::from_bytes(bytes)),
                 0x09 => return ParseOk(FieldValue, TAB, str::from_bytes(bytes)),
                 -1   => return ParseOk(FieldValue, EOF, str::from_bytes(bytes)),
                 _    => return ParseError(~"invalid byte detected")
             }
+            match self.check_value_limits(label, bytes.len()) {
+                Some(reason) => return ParseError(reason),
+                None => ()
+            }
+            self.bump();
+        }
+    }
+
+    priv fn parse_length_prefixed_value(&self, label: &str) -> ParseResult<~str> {
+        let mut len = 0u;
+        let mut ndigits = 0u;
+        while *self.cur >= 0x30 && *self.cur <= 0x39 {
+            len = len * 10 + (*self.cur - 0x30) as uint;
+            ndigits += 1;
+            self.bump();
+        }
+        if ndigits == 0 || *self.cur != 0x3a {
+            return ParseError(~"invalid value length");
+        }
+        match self.check_value_limits(label, len) {
+            Some(reason) => return ParseError(reason),
+            None => ()
+        }
+        let mut bytes = ~[];
+        for len.times {
+            self.bump();
+            if self.eof() {
+                return ParseError(~"EOF while parsing length-prefixed value");
+            }
+            bytes.push(*self.cur as u8);
+            match self.check_position_limits() {
+                Some(reason) => return ParseError(reason),
+                None => ()
+            }
+        }
+        self.bump();
+        if !str::is_utf8(bytes) {
+            return ParseError(~"value is not valid UTF-8");
+        }
+        let value = str::from_bytes(bytes);
+        match *self.cur {
+            0x0d => self.consume_forward_LF(value),
+            0x0a => ParseOk(FieldValue, NL, value),
+            0x09 => ParseOk(FieldValue, TAB, value),
+            -1   => ParseOk(FieldValue, EOF, value),
+            _    => ParseError(~"invalid byte detected")
+        }
+    }
+
+    priv fn insert_field(&self, record: &mut Record, label: ~str, value: ~str) -> Option<~str> {
+        if record.contains_key(&label) {
+            match self.opts.duplicates {
+                FirstWins => return None,
+                LastWins => (),
+                RejectDuplicates => return Some(fmt!("duplicate label: %s", label))
+            }
+        }
+        record.insert(label, value);
+        None
+    }
+
+    priv fn block_end(&self, value: &str) -> Option<~str> {
+        match self.opts.block_value_markers {
+            Some((ref start, ref end)) if str::eq_slice(*start, value) => Some(copy *end),
+            _ => None
+        }
+    }
+
+    priv fn parse_block_value(&self, label: &str, end: &str) -> ParseResult<~str> {
+        let mut lines = ~[];
+        let mut len = 0u;
+        loop {
             self.bump();
+            let mut bytes = ~[];
+            while *self.cur != 0x0a && !self.eof() {
+                bytes.push(*self.cur as u8);
+                match self.check_value_limits(label, len + bytes.len()) {
+                    Some(reason) => return ParseError(reason),
+                    None => ()
+                }
+                self.bump();
+            }
+            len += bytes.len() + 1;
+            if bytes.last_opt() == Some(&0x0d) { bytes.pop(); }
+            let line = str::from_bytes(bytes);
+            if str::eq_slice(line, end) {
+                return ParseOk(FieldValue, NL, str::connect(lines, "\n"));
+            }
+            if self.eof() {
+                return ParseError(~"EOF while parsing block value");
+            }
+            lines.push(line);
         }
     }
 
+    priv fn check_value_limits(&self, label: &str, len: uint) -> Option<~str> {
+        if len > self.opts.limits.max_value_len {
+            return Some(fmt!("value of %s exceeds %u bytes", label,
+                             self.opts.limits.max_value_len));
+        }
+        self.check_position_limits()
+    }
+
+    // called once `cur`, byte `pos` of the input, has been stored.
+    priv fn check_position_limits(&self) -> Option<~str> {
+        let limits = &self.opts.limits;
+        if *self.pos - *self.record_start + 1 > limits.max_line_len {
+            Some(fmt!("line exceeds %u bytes", limits.max_line_len))
+        } else if self.over_budget() {
+            Some(self.budget_error())
+        } else {
+            None
+        }
+    }
+
+    // whether more than `max_total_bytes` have been read, counting `cur`.
+    priv fn over_budget(&self) -> bool {
+        let read = if self.eof() { *self.pos } else { *self.pos + 1 };
+        read > self.opts.limits.max_total_bytes
+    }
+
+    priv fn budget_error(&self) -> ~str {
+        fmt!("input exceeds %u bytes", self.opts.limits.max_total_bytes)
+    }
+
     priv fn consume_forward_LF(&self, rv: ~str) -> ParseResult<~str> {
         self.bump();
         if *self.cur != 0x0a {
@@ -220,71 +1599,3248 @@ pub impl<'self, T: io::Reader> LTSVParser<'self, T> {
         }
     }
 
+    priv fn at_inline_comment(&self) -> bool {
+        match self.opts.inline_comment {
+            Some(c) => *self.cur == c as int,
+            None => false
+        }
+    }
+
+    priv fn skip_inline_comment(&self) -> ParseDelimiter {
+        while *self.cur != 0x0a && !self.eof() && !self.over_budget() {
+            self.bump();
+        }
+        self.bump();
+        self.skip_whitespaces();
+        if self.eof() { EOF } else { NL }
+    }
+
     priv fn bump(&self) {
         if !self.eof() {
             *self.cur = self.rd.read_byte();
+            *self.pos += 1;
         }
     }
 
     priv fn skip_whitespaces(&self) {
-        while char::is_whitespace(*self.cur as char) {
+        if !self.opts.skip_whitespace {
+            return;
+        }
+        // whitespace is not kept, but still counts against the byte budget.
+        while char::is_whitespace(*self.cur as char) && !self.over_budget() {
             self.bump();
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use core::io::WriterUtil;
+pub struct TailParser<T> {
+    priv rd: T,
+    priv pos: uint,
+    priv pending: ~[u8]
+}
 
-    fn mk_record_string() -> ~str {
-        do io::with_str_writer |wr| {
-            // genzairyou
-            wr.write_str(fmt!("%s:%s\t", "tofu", "豆"));
-            wr.write_str(fmt!("%s:%s\t", "kamaboko", "魚"));
-            wr.write_str(fmt!("%s:%s\n", "sukonbu", "海藻"));
-            // konomi
-            wr.write_str(fmt!("%s:%s\t", "tofu", "好き"));
-            wr.write_str(fmt!("%s:%s\t", "kamaboko", "普通"));
-            wr.write_str(fmt!("%s:%s\n", "sukonbu", "苦手"));
+pub impl<T: io::Reader> TailParser<T> {
+    fn new(rd: T) -> TailParser<T> {
+        TailParser { rd: rd, pos: 0, pending: ~[] }
+    }
+
+    fn poll(&mut self) -> Result<~[Record], ~str> {
+        // seeking also clears a sticky EOF left by the previous poll.
+        self.rd.seek(self.pos as int, io::SeekSet);
+        loop {
+            let b = self.rd.read_byte();
+            if b == -1 { break; }
+            self.pending.push(b as u8);
+            self.pos += 1;
         }
+        let end = match vec::rposition(self.pending, |b| *b == 0x0a) {
+            Some(i) => i + 1,
+            None => return Ok(~[])
+        };
+        let complete = self.pending.slice(0, end).to_vec();
+        self.pending = self.pending.slice(end, self.pending.len()).to_vec();
+        io::with_bytes_reader(complete, |rd| LTSVParser::new(rd).read_records())
     }
+}
 
-    #[test]
-    fn test_parse_simple() {
-        let records = io::with_str_reader(~"a:1\tb:2", |rd| rd.read_ltsv());
-        assert_eq!(records.len(), 1);
+/// Records read by `read_ltsv_spilling`. At most `mem_limit` of the most
+/// recent records stay in memory; older ones are written to a temporary
+/// file that is parsed again on every `each`, trading repeated disk reads
+/// for a bounded memory footprint. The file is removed on drop.
+pub struct SpilledRecords {
+    priv dir: Option<Path>,
+    priv wr: Option<@io::Writer>,
+    priv nspilled: uint,
+    priv recent: ~[Record]
+}
+
+static SPILL_FILE: &'static str = "spill.ltsv";
+
+pub impl SpilledRecords {
+    fn len(&self) -> uint {
+        self.nspilled + self.recent.len()
     }
 
-    #[test]
-    fn test_parse_full() {
-        let s = mk_record_string();
-        let records = io::with_str_reader(s, |rd| rd.read_ltsv());
-        assert_eq!(records.len(), 2);
+    fn spilled(&self) -> uint {
+        self.nspilled
     }
 
-    #[test]
-    fn test_parse_ltsv_trailing_nl_and_write() {
-        let s = mk_record_string();
-        let records_1 = io::with_str_reader(s, |rd| rd.read_ltsv());
-        let s2 = io::with_str_writer(|wr| wr.write_ltsv(records_1));
-        let records_2 = io::with_str_reader(s2, |rd| rd.read_ltsv());
-        assert_eq!(records_1, records_2);
+    priv fn spill(&mut self) -> Result<(), ~str> {
+        if self.wr.is_none() {
+            let dir = match tempfile::mkdtemp(&os::tmpdir(), "ltsv") {
+                Some(dir) => dir,
+                None => return Err(~"failed to create a spill directory")
+            };
+            self.dir = Some(copy dir);
+            match io::file_writer(&dir.push(SPILL_FILE), [io::Create, io::Truncate]) {
+                Ok(wr) => self.wr = Some(wr),
+                Err(reason) => return Err(reason)
+            }
+        }
+        self.wr.get().write_ltsv(self.recent);
+        self.nspilled += self.recent.len();
+        self.recent = ~[];
+        Ok(())
     }
+}
 
-    #[test]
-    fn test_each_read_each_record() {
-        let s = mk_record_string();
-        let ks = [~"tofu", ~"kamaboko", ~"sukonbu"];
-        let vs = [~"豆", ~"魚", ~"海藻", ~"好き", ~"普通", ~"苦手"];
-        do io::with_str_reader(s) |rd| {
-            for rd.each_ltsv_record |record| {
-                for record.each |&(k, v)| {
-                    assert!(ks.contains(k));
-                    assert!(vs.contains(v));
+impl BaseIter<Record> for SpilledRecords {
+    fn each(&self, blk: &fn(v: &Record) -> bool) {
+        match self.dir {
+            Some(ref dir) => {
+                let rd = match io::file_reader(&dir.push(SPILL_FILE)) {
+                    Ok(rd) => rd,
+                    Err(reason) => fail!(reason)
+                };
+                for rd.each_ltsv_record |record| {
+                    if !blk(record) { return; }
                 }
             }
+            None => ()
+        }
+        for self.recent.each |record| {
+            if !blk(record) { return; }
+        }
+    }
+    fn size_hint(&self) -> Option<uint> {
+        Some(self.len())
+    }
+}
+
+impl Drop for SpilledRecords {
+    fn finalize(&self) {
+        match self.dir {
+            Some(ref dir) => {
+                os::remove_file(&dir.push(SPILL_FILE));
+                os::remove_dir(dir);
+            }
+            None => ()
+        }
+    }
+}
+
+pub struct BloomFilter {
+    priv bits: Bitv,
+    priv hashes: uint
+}
+
+pub impl BloomFilter {
+    /// Creates an empty filter of `bits` bits probed by `hashes` hash
+    /// functions; both must be positive.
+    fn new(bits: uint, hashes: uint) -> Result<BloomFilter, ~str> {
+        if bits == 0 || hashes == 0 {
+            return Err(~"bits and hashes must be positive");
+        }
+        Ok(BloomFilter { bits: Bitv::new(bits, false), hashes: hashes })
+    }
+
+    fn insert(&mut self, value: &str) {
+        for self.indices(value).each |&i| {
+            self.bits.set(i, true);
+        }
+    }
+
+    fn contains(&self, value: &str) -> bool {
+        self.indices(value).all(|&i| self.bits.get(i))
+    }
+
+    // double hashing: the k-th index is h1 + k * h2.
+    priv fn indices(&self, value: &str) -> ~[uint] {
+        let h1 = fnv1a(FNV_OFFSET_BASIS, value);
+        let h2 = fnv1a(FNV_OFFSET_BASIS ^ 0x5bd1e995, value) | 1;
+        let nbits = self.bits.nbits as u64;
+        do vec::from_fn(self.hashes) |k| {
+            ((h1 + (k as u64) * h2) % nbits) as uint
+        }
+    }
+}
+
+pub fn get_parsed_locale<T: FromStr>(record: &Record, label: &str,
+                                     decimal_comma: bool) -> Option<T> {
+    match record.find(&label.to_owned()) {
+        Some(value) if decimal_comma => from_str::from_str(str::replace(*value, ",", ".")),
+        Some(value) => from_str::from_str(*value),
+        None => None
+    }
+}
+
+pub fn changed_records<A: io::Reader, B: io::Reader>(old: &A, new: &B, key_label: &str)
+                                                    -> Result<~[Record], ~str> {
+    let key = key_label.to_owned();
+    let mut index = LinearMap::new();
+    let old_records = match LTSVParser::new(old).read_records() {
+        Ok(records) => records,
+        Err(reason) => return Err(reason)
+    };
+    for old_records.eachi |i, record| {
+        match record.find(&key) {
+            Some(value) => { index.insert(copy *value, record); }
+            None => return Err(missing_label(key_label, i))
+        }
+    }
+    let new_records = match LTSVParser::new(new).read_records() {
+        Ok(records) => records,
+        Err(reason) => return Err(reason)
+    };
+    let mut changed = ~[];
+    for new_records.eachi |i, record| {
+        match record.find(&key) {
+            Some(value) => match index.find(value) {
+                Some(old_record) if **old_record == *record => (),
+                _ => changed.push(copy *record)
+            },
+            None => return Err(missing_label(key_label, i))
+        }
+    }
+    Ok(changed)
+}
+
+pub fn write_ltsv_numbered<W: io::Writer>(wr: &W, records: &[Record], label: &str) {
+    for records.eachi |i, record| {
+        let mut record = copy *record;
+        record.insert(label.to_owned(), (i + 1).to_str());
+        wr.write_ltsv_record(&record);
+        wr.write_char('\n');
+    }
+}
+
+pub fn read_ltsv_from_json_field<R: io::Reader>(rd: &R, field: &str) -> Result<~[Record], ~str> {
+    let key = field.to_owned();
+    let mut records = ~[];
+    let mut lineno = 0u;
+    let mut err = None;
+    for rd.each_line |line| {
+        lineno += 1;
+        if str::is_whitespace(line) { loop; }
+        let payload = match json::from_str(line) {
+            Ok(json::Object(obj)) => match obj.find(&key) {
+                Some(&json::String(ref s)) => copy *s,
+                Some(_) => { err = Some(fmt!("line %u: %s is not a string", lineno, field)); break; }
+                None => { err = Some(fmt!("line %u: missing field %s", lineno, field)); break; }
+            },
+            Ok(_) => { err = Some(fmt!("line %u: not a JSON object", lineno)); break; }
+            Err(e) => { err = Some(fmt!("line %u: %s", lineno, *e.msg)); break; }
+        };
+        match io::with_str_reader(payload, |rd| LTSVParser::new(rd).read_records()) {
+            Ok(rs) => records.push_all_move(rs),
+            Err(reason) => { err = Some(fmt!("line %u: %s", lineno, reason)); break; }
+        }
+    }
+    match err {
+        Some(reason) => Err(reason),
+        None => Ok(records)
+    }
+}
+
+fn is_label_byte(c: int) -> bool {
+    match c {
+        0x30..0x39 | 0x41..0x5a | 0x61..0x7a | 0x5f |
+        0x2e | 0x2d => true,
+        _ => false
+    }
+}
+
+fn is_valid_label(label: &str) -> bool {
+    label.len() > 0 && str::all(label, |c| is_label_byte(c as int))
+}
+
+pub fn record_from_kv_args(args: &[~str], sep: char) -> Result<Record, ~str> {
+    let mut record = LinearMap::new();
+    for args.each |arg| {
+        let i = match str::find_char(*arg, sep) {
+            Some(i) => i,
+            None => return Err(fmt!("missing '%c' in argument: %s", sep, *arg))
+        };
+        let label = arg.slice(0, i).to_owned();
+        if !is_valid_label(label) {
+            return Err(fmt!("invalid label in argument: %s", *arg));
+        }
+        record.insert(label, arg.slice(i + char::len_utf8_bytes(sep), arg.len()).to_owned());
+    }
+    Ok(record)
+}
+
+pub fn write_fixed_width<W: io::Writer>(wr: &W, records: &[Record], columns: &[(~str, uint)]) {
+    for records.each |record| {
+        for columns.each |&(ref label, width)| {
+            let value = match record.find(label) {
+                Some(value) => copy *value,
+                None => ~""
+            };
+            wr.write_str(fit_width(value, width));
+        }
+        wr.write_char('\n');
+    }
+}
+
+fn is_value_byte(c: int) -> bool {
+    match c {
+        0x01..0x08 | 0x0b | 0x0c |
+        0x0e..0xff => true,
+        _ => false
+    }
+}
+
+fn is_valid_value(value: &str) -> bool {
+    for str::each(value) |b| {
+        if !is_value_byte(b as int) { return false; }
+    }
+    true
+}
+
+fn check_record(record: &Record) -> Result<(), ~str> {
+    for record.each |&(label, value)| {
+        if !is_valid_label(*label) {
+            return Err(fmt!("invalid label: %s", *label));
+        }
+        if !is_valid_value(*value) {
+            return Err(fmt!("invalid value for label %s", *label));
+        }
+    }
+    Ok(())
+}
+
+fn assert_valid_ltsv_bytes(bytes: &[u8]) -> Result<(), ~str> {
+    let mut i = 0u;
+    let mut lineno = 1u;
+    while i < bytes.len() {
+        if bytes[i] == 0x0a {
+            i += 1;
+            lineno += 1;
+            loop;
+        }
+        loop {
+            let start = i;
+            while i < bytes.len() && is_label_byte(bytes[i] as int) { i += 1; }
+            if i == start {
+                return Err(fmt!("line %u: empty or invalid label", lineno));
+            }
+            if i == bytes.len() || bytes[i] != 0x3a {
+                return Err(fmt!("line %u: expected ':' after label", lineno));
+            }
+            i += 1;
+            while i < bytes.len() && is_value_byte(bytes[i] as int) { i += 1; }
+            if i == bytes.len() {
+                return Ok(());
+            }
+            match bytes[i] {
+                0x09 => i += 1,
+                0x0a => break,
+                _ => return Err(fmt!("line %u: invalid byte in value", lineno))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn skip_whitespace_bytes(bytes: &[u8], i: uint) -> uint {
+    let mut i = i;
+    while i < bytes.len() && char::is_whitespace(bytes[i] as char) {
+        i += 1;
+    }
+    i
+}
+
+fn record_to_line(record: &Record) -> ~str {
+    do io::with_str_writer |wr| {
+        wr.write_ltsv_record(record);
+        wr.write_char('\n');
+    }
+}
+
+fn sorted_labels(record: &Record) -> ~[~str] {
+    let mut labels = ~[];
+    for record.each_key |label| {
+        labels.push(copy *label);
+    }
+    sort::tim_sort(labels);
+    labels
+}
+
+fn canonical_string(record: &Record) -> ~str {
+    let fields = do sorted_labels(record).map |label| {
+        fmt!("%s:%s", *label, *record.get(label))
+    };
+    str::connect(fields, "\t")
+}
+
+fn fnv1a(h: u64, s: &str) -> u64 {
+    let mut h = h;
+    for str::each(s) |b| {
+        h ^= b as u64;
+        h *= 0x100000001b3;
+    }
+    h
+}
+
+static FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+fn hex_u64(h: u64) -> ~str {
+    let digits = "0123456789abcdef";
+    let mut rv = ~"";
+    for uint::range(0, 16) |i| {
+        let nibble = ((h >> (((15 - i) * 4) as u64)) & 0xf) as uint;
+        str::push_char(&mut rv, digits[nibble] as char);
+    }
+    rv
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffff_u32;
+    for bytes.each |&b| {
+        crc ^= b as u32;
+        for 8.times {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) | (bytes[1] as u32 << 8) |
+    (bytes[2] as u32 << 16) | (bytes[3] as u32 << 24)
+}
+
+fn truncate_value(value: &str, max_len: uint) -> ~str {
+    if value.len() <= max_len {
+        return value.to_owned();
+    }
+    let mut end = max_len;
+    while !str::is_char_boundary(value, end) { end -= 1; }
+    value.slice(0, end).to_owned() + "…"
+}
+
+fn json_string(s: &str) -> ~str {
+    let mut rv = ~"\"";
+    for str::each_char(s) |c| {
+        match c {
+            '"' => str::push_str(&mut rv, "\\\""),
+            '\\' => str::push_str(&mut rv, "\\\\"),
+            '\n' => str::push_str(&mut rv, "\\n"),
+            '\r' => str::push_str(&mut rv, "\\r"),
+            '\t' => str::push_str(&mut rv, "\\t"),
+            c if c < ' ' || c == '\x7f' => str::push_str(&mut rv, fmt!("\\u%04x", c as uint)),
+            c => str::push_char(&mut rv, c)
+        }
+    }
+    str::push_char(&mut rv, '"');
+    rv
+}
+
+fn decode_base64(input: &[u8]) -> Result<~[u8], ~str> {
+    let mut rv = ~[];
+    let mut acc = 0u32;
+    let mut nbits = 0u;
+    let mut padding = 0u;
+    for input.eachi |i, &b| {
+        let v = match b as char {
+            'A'..'Z' => b - 'A' as u8,
+            'a'..'z' => b - 'a' as u8 + 26,
+            '0'..'9' => b - '0' as u8 + 52,
+            '+' => 62,
+            '/' => 63,
+            '=' => { padding += 1; loop; }
+            ' ' | '\t' | '\r' | '\n' => loop,
+            _ => return Err(fmt!("invalid base64 byte at offset %u", i))
+        };
+        if padding > 0 {
+            return Err(fmt!("base64 data after padding at offset %u", i));
+        }
+        acc = (acc << 6) | v as u32;
+        nbits += 6;
+        if nbits >= 8 {
+            nbits -= 8;
+            rv.push((acc >> nbits as u32) as u8);
         }
     }
+    // leftover bits of a final 2 or 3 character group, optionally padded.
+    match (nbits, padding) {
+        (0, 0) | (4, 0) | (4, 2) | (2, 0) | (2, 1) => Ok(rv),
+        _ => Err(~"invalid base64 length")
+    }
+}
+
+fn fit_width(s: &str, width: uint) -> ~str {
+    let mut rv = ~"";
+    let mut n = 0u;
+    for str::each_char(s) |c| {
+        if n == width { break; }
+        str::push_char(&mut rv, c);
+        n += 1;
+    }
+    while n < width {
+        str::push_char(&mut rv, ' ');
+        n += 1;
+    }
+    rv
+}
+
+/// Writes each record of `rd` to `wr`, sleeping between records for the
+/// difference of their `ts_label` values (in seconds) multiplied by `speed`.
+/// A `speed` of 1.0 replays in real time and 0.0 writes without delay;
+/// timestamps that go backwards do not sleep.
+pub fn replay_ltsv<R: io::Reader, W: io::Writer>(rd: &R, wr: &W, ts_label: &str,
+                                                 speed: f64) -> Result<(), ~str> {
+    let iotask = uv::global_loop::get();
+    let mut prev = None;
+    do LTSVParser::new(rd).try_each_record |i, record| {
+        match numeric_value(&record, ts_label, i) {
+            Err(reason) => Err(reason),
+            Ok(ts) => {
+                match prev {
+                    Some(p) if ts > p => {
+                        let ms = ((ts - p) * speed * 1000.0) as uint;
+                        if ms > 0 { timer::sleep(&iotask, ms); }
+                    }
+                    _ => ()
+                }
+                prev = Some(ts);
+                wr.write_ltsv_record(&record);
+                wr.write_char('\n');
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn normalize_eol_ltsv<R: io::Reader, W: io::Writer>(rd: &R, wr: &W) -> Result<uint, ~str> {
+    let mut n = 0u;
+    let rv = do LTSVParser::new(rd).each_record |record| {
+        wr.write_ltsv_record(&record);
+        wr.write_char('\n');
+        n += 1;
+        true
+    };
+    with_value(rv, n)
+}
+
+pub fn write_ltsv_rotating(base_path: &Path, records: &[Record], max_bytes: uint)
+                           -> Result<uint, ~str> {
+    let mut nfiles = 0u;
+    let mut written = 0u;
+    let mut wr: Option<@io::Writer> = None;
+    for records.each |record| {
+        match check_record(record) {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+        let line = record_to_line(record);
+        if wr.is_none() || (written > 0 && written + line.len() > max_bytes) {
+            let path = Path(fmt!("%s.%u", base_path.to_str(), nfiles));
+            match io::file_writer(&path, [io::Create, io::Truncate]) {
+                Ok(w) => wr = Some(w),
+                Err(reason) => return Err(reason)
+            }
+            nfiles += 1;
+            written = 0;
+        }
+        wr.get().write_str(line);
+        written += line.len();
+    }
+    Ok(nfiles)
+}
+
+/// Parses an in-memory string by walking its bytes directly instead of
+/// going through an `io::Reader`. It accepts the same input as
+/// `LTSVParser::new` with default options.
+pub fn parse_ltsv_chars(s: &str) -> Result<~[Record], ~str> {
+    let bytes = str::as_bytes_slice(s);
+    let len = bytes.len();
+    let byte_at = |i: uint| if i < len { bytes[i] as int } else { -1 };
+    let mut records = ~[];
+    let mut record = LinearMap::new();
+    let mut in_record = false;
+    let mut i = skip_whitespace_bytes(bytes, 0);
+    // like the reader, input of nothing but whitespace is a missing label.
+    if i == len && len > 0 {
+        return Err(~"EOF while parsing field label");
+    }
+    while i < len {
+        let start = i;
+        while is_label_byte(byte_at(i)) { i += 1; }
+        match byte_at(i) {
+            0x3a if i == start => return Err(~"label is empty"),
+            0x3a => (),
+            -1   => return Err(~"EOF while parsing field label"),
+            _    => return Err(~"invalid byte detected")
+        }
+        let label = str::from_bytes(bytes.slice(start, i));
+        i += 1;
+        let start = i;
+        while is_value_byte(byte_at(i)) { i += 1; }
+        let value = str::from_bytes(bytes.slice(start, i));
+        let delim = match byte_at(i) {
+            0x0d if byte_at(i + 1) == 0x0a => { i += 2; NL }
+            0x0d => return Err(~"CR detected, but not provided with LF"),
+            0x0a => { i += 1; NL }
+            0x09 => { i += 1; TAB }
+            -1   => EOF,
+            _    => return Err(~"invalid byte detected")
+        };
+        record.insert(label, value);
+        in_record = delim == TAB;
+        if !in_record {
+            records.push(record);
+            record = LinearMap::new();
+        }
+        i = skip_whitespace_bytes(bytes, i);
+    }
+    if in_record {
+        return Err(~"EOF while parsing field label");
+    }
+    Ok(records)
+}
+
+pub fn summarize_record(record: &Record, labels: &[~str], sep: &str) -> ~str {
+    let values = do labels.map |label| {
+        match record.find(label) {
+            Some(value) => copy *value,
+            None => ~""
+        }
+    };
+    str::connect(values, sep)
+}
+
+pub fn record_hash(record: &Record) -> ~str {
+    hex_u64(fnv1a(FNV_OFFSET_BASIS, canonical_string(record)))
+}
+
+/// Writes `records`, adding to each a `hash_label` field holding the SHA-1
+/// of the previous record's hash and this record's fields, so editing,
+/// dropping or reordering records breaks the chain that
+/// `verify_ltsv_hash_chain` checks. It is an error for a record to carry
+/// `hash_label` already.
+pub fn write_ltsv_hash_chained<W: io::Writer>(wr: &W, records: &[Record], hash_label: &str)
+                                              -> Result<(), ~str> {
+    if !is_valid_label(hash_label) {
+        return Err(fmt!("invalid label: %s", hash_label));
+    }
+    let key = hash_label.to_owned();
+    for records.eachi |i, record| {
+        match check_record(record) {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+        if record.contains_key(&key) {
+            return Err(fmt!("record %u: already has label %s", i, hash_label));
+        }
+    }
+    let mut prev = ~"";
+    for records.each |record| {
+        let mut record = copy *record;
+        let h = chain_hash(prev, &record);
+        record.insert(hash_label.to_owned(), copy h);
+        wr.write_ltsv_record(&record);
+        wr.write_char('\n');
+        prev = h;
+    }
+    Ok(())
+}
+
+fn chain_hash(prev: &str, record: &Record) -> ~str {
+    let sha = sha1::sha1();
+    sha.input_str(prev);
+    sha.input_str("\t");
+    sha.input_str(canonical_string(record));
+    sha.result_str()
+}
+
+pub fn write_ltsv_reversed<W: io::Writer>(wr: &W, records: &[Record]) {
+    for vec::rev_each(records) |record| {
+        wr.write_ltsv_record(record);
+        wr.write_char('\n');
+    }
+}
+
+pub fn write_ltsv_gz<W: io::Writer>(wr: &W, records: &[Record]) -> Result<(), ~str> {
+    for records.each |record| {
+        match check_record(record) {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+    }
+    let data = io::with_bytes_writer(|wr| wr.write_ltsv(records));
+    // gzip member header: magic, deflate, no flags, no mtime, unknown OS.
+    wr.write([0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff]);
+    wr.write(flate::deflate_bytes(data));
+    wr.write_le_u32(crc32(data));
+    wr.write_le_u32(data.len() as u32);
+    Ok(())
+}
+
+pub fn read_ltsv_gz<R: io::Reader>(rd: &R) -> Result<~[Record], ~str> {
+    let bytes = rd.read_whole_stream();
+    if bytes.len() < 18 || bytes[0] != 0x1f || bytes[1] != 0x8b {
+        return Err(~"not a gzip stream");
+    }
+    if bytes[2] != 0x08 {
+        return Err(~"unsupported gzip compression method");
+    }
+    let flags = bytes[3];
+    let mut i = 10u;
+    if flags & 0x04 != 0 {
+        if i + 2 > bytes.len() { return Err(~"truncated gzip header"); }
+        i += 2 + (bytes[i] as uint | bytes[i + 1] as uint << 8);
+    }
+    for [0x08u8, 0x10].each |&flag| {
+        if flags & flag != 0 {
+            while i < bytes.len() && bytes[i] != 0 { i += 1; }
+            i += 1;
+        }
+    }
+    if flags & 0x02 != 0 { i += 2; }
+    if i + 8 > bytes.len() {
+        return Err(~"truncated gzip header");
+    }
+    let trailer = bytes.slice(bytes.len() - 8, bytes.len());
+    // `flate::inflate_bytes` fails the task on corrupt input, so run it in
+    // a child task on its own copy of the body.
+    let body = bytes.slice(i, bytes.len() - 8).to_vec();
+    let inflated = do task::try { flate::inflate_bytes(body) };
+    let data = match inflated {
+        Ok(data) => data,
+        Err(_) => return Err(~"corrupt gzip body")
+    };
+    if crc32(data) != le_u32(trailer.slice(0, 4)) || data.len() as u32 != le_u32(trailer.slice(4, 8)) {
+        return Err(~"gzip checksum mismatch");
+    }
+    io::with_bytes_reader(data, |rd| LTSVParser::new(rd).read_records())
+}
+
+fn detect_type(value: &str) -> ValueType {
+    if from_str::from_str::<i64>(value).is_some() {
+        IntType
+    } else if from_str::from_str::<f64>(value).is_some() {
+        FloatType
+    } else if value == "true" || value == "false" {
+        BoolType
+    } else {
+        StringType
+    }
+}
+
+fn type_name(ty: ValueType) -> &'static str {
+    match ty {
+        IntType => "int",
+        FloatType => "float",
+        BoolType => "bool",
+        StringType => "string"
+    }
+}
+
+fn type_from_name(name: &str) -> Option<ValueType> {
+    match name {
+        "int" => Some(IntType),
+        "float" => Some(FloatType),
+        "bool" => Some(BoolType),
+        "string" => Some(StringType),
+        _ => None
+    }
+}
+
+fn value_matches(value: &str, ty: ValueType) -> bool {
+    unify_types(detect_type(value), ty) == ty
+}
+
+fn unify_types(a: ValueType, b: ValueType) -> ValueType {
+    match (a, b) {
+        (IntType, FloatType) | (FloatType, IntType) => FloatType,
+        (a, b) if a == b => a,
+        _ => StringType
+    }
+}
+
+/// Writes `records` with every value longer than `max_value_len` bytes cut
+/// at a char boundary and suffixed with `…`. This is meant for previews:
+/// the output cannot be read back into the original records.
+pub fn write_ltsv_truncated<W: io::Writer>(wr: &W, records: &[Record], max_value_len: uint) {
+    for records.each |record| {
+        let mut truncated = LinearMap::new();
+        for record.each |&(label, value)| {
+            truncated.insert(copy *label, truncate_value(*value, max_value_len));
+        }
+        wr.write_ltsv_record(&truncated);
+        wr.write_char('\n');
+    }
+}
+
+pub fn split_field_into_labels(record: &mut Record, label: &str, pair_sep: char, kv_sep: char,
+                               prefix: &str) -> Result<(), ~str> {
+    let value = match record.find(&label.to_owned()) {
+        Some(value) => copy *value,
+        None => return Err(fmt!("missing label %s", label))
+    };
+    let mut fields = ~[];
+    for str::split_char(value, pair_sep).each |pair| {
+        let pair = str::trim(*pair);
+        if pair.is_empty() { loop; }
+        let i = match str::find_char(pair, kv_sep) {
+            Some(i) => i,
+            None => return Err(fmt!("missing '%c' in %s: %s", kv_sep, label, pair))
+        };
+        let sub_label = prefix.to_owned() + pair.slice(0, i);
+        if !is_valid_label(sub_label) {
+            return Err(fmt!("invalid label: %s", sub_label));
+        }
+        fields.push((sub_label, pair.slice(i + char::len_utf8_bytes(kv_sep), pair.len()).to_owned()));
+    }
+    for fields.each |&(ref sub_label, ref sub_value)| {
+        record.insert(copy *sub_label, copy *sub_value);
+    }
+    Ok(())
+}
+
+pub fn record_to_json(record: &Record) -> ~str {
+    let mut members = ~[];
+    for record.each |&(label, value)| {
+        members.push(fmt!("%s:%s", json_string(*label), json_string(*value)));
+    }
+    ~"{" + str::connect(members, ",") + "}"
+}
+
+/// Like `record_to_json`, but with members sorted by label bytes, so equal
+/// records always give the same bytes. There is no whitespace, non-ASCII
+/// characters are written as UTF-8, and `"`, `\` and control characters
+/// are escaped, using the short forms where JSON has them.
+pub fn record_to_canonical_json(record: &Record) -> ~str {
+    let members = do sorted_labels(record).map |label| {
+        fmt!("%s:%s", json_string(*label), json_string(*record.get(label)))
+    };
+    ~"{" + str::connect(members, ",") + "}"
+}
+
+pub fn write_json_array<R: io::Reader, W: io::Writer>(rd: &R, wr: &W) -> Result<uint, ~str> {
+    let mut n = 0u;
+    wr.write_char('[');
+    let rv = do LTSVParser::new(rd).each_record |record| {
+        if n > 0 { wr.write_char(','); }
+        wr.write_str(record_to_json(&record));
+        n += 1;
+        true
+    };
+    wr.write_char(']');
+    with_value(rv, n)
+}
+
+pub fn read_ltsv_base64<R: io::Reader>(rd: &R) -> Result<~[Record], ~str> {
+    match decode_base64(rd.read_whole_stream()) {
+        Ok(bytes) => io::with_bytes_reader(bytes, |rd| LTSVParser::new(rd).read_records()),
+        Err(reason) => Err(reason)
+    }
+}
+
+pub fn write_ltsv_shuffled<W: io::Writer>(wr: &W, records: &[Record], seed: u64) {
+    let mut rng = Rng64::new(seed);
+    let mut indices = vec::from_fn(records.len(), |i| i);
+    let n = indices.len();
+    for uint::range(1, n) |k| {
+        let i = n - k;
+        let j = rng.next_below(i + 1);
+        vec::swap(indices, i, j);
+    }
+    for indices.each |&i| {
+        wr.write_ltsv_record(&records[i]);
+        wr.write_char('\n');
+    }
+}
+
+pub fn record_sequence_diff<A: io::Reader, B: io::Reader>(a: &A, b: &B)
+                                                         -> Result<~[DiffOp], ~str> {
+    let a = match LTSVParser::new(a).read_records() {
+        Ok(records) => records,
+        Err(reason) => return Err(reason)
+    };
+    let b = match LTSVParser::new(b).read_records() {
+        Ok(records) => records,
+        Err(reason) => return Err(reason)
+    };
+    let (n, m) = (a.len(), b.len());
+    // cost[i][j]: edit distance between a[i..] and b[j..].
+    let mut cost = vec::from_elem(n + 1, vec::from_elem(m + 1, 0u));
+    for uint::range(0, n + 1) |k| {
+        let i = n - k;
+        for uint::range(0, m + 1) |l| {
+            let j = m - l;
+            cost[i][j] = if i == n {
+                m - j
+            } else if j == m {
+                n - i
+            } else if a[i] == b[j] {
+                cost[i + 1][j + 1]
+            } else {
+                1 + uint::min(cost[i + 1][j + 1], uint::min(cost[i + 1][j], cost[i][j + 1]))
+            };
+        }
+    }
+    let mut ops = ~[];
+    let (mut i, mut j) = (0u, 0u);
+    while i < n || j < m {
+        if i < n && j < m && a[i] == b[j] {
+            i += 1;
+            j += 1;
+        } else if i < n && j < m && cost[i][j] == cost[i + 1][j + 1] + 1 {
+            ops.push(Replace(copy a[i], copy b[j]));
+            i += 1;
+            j += 1;
+        } else if i < n && cost[i][j] == cost[i + 1][j] + 1 {
+            ops.push(Delete(copy a[i]));
+            i += 1;
+        } else {
+            ops.push(Insert(copy b[j]));
+            j += 1;
+        }
+    }
+    Ok(ops)
+}
+
+pub fn write_ltsv_with_offsets<W: io::Writer>(wr: &W, records: &[Record], label: &str)
+                                              -> Result<~[uint], ~str> {
+    let mut offsets = ~[];
+    let mut offset = 0u;
+    for records.each |record| {
+        let mut record = copy *record;
+        record.insert(label.to_owned(), offset.to_str());
+        match check_record(&record) {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+        let line = record_to_line(&record);
+        wr.write_str(line);
+        offsets.push(offset);
+        offset += line.len();
+    }
+    Ok(offsets)
+}
+
+pub fn write_tsv_compact<W: io::Writer>(wr: &W, records: &[Record], common_prefix: &str,
+                                        columns: &[~str]) -> Result<(), ~str> {
+    for columns.each |column| {
+        if !str::starts_with(*column, common_prefix) {
+            return Err(fmt!("column %s does not start with %s", *column, common_prefix));
+        }
+    }
+    let mut rows = ~[];
+    for records.eachi |i, record| {
+        let mut row = ~[];
+        for columns.each |column| {
+            let value = match record.find(column) {
+                Some(value) => copy *value,
+                None => ~""
+            };
+            if !is_valid_value(value) {
+                return Err(fmt!("record %u: invalid value for label %s", i, *column));
+            }
+            row.push(value);
+        }
+        rows.push(str::connect(row, "\t"));
+    }
+    let header = do columns.map |column| {
+        column.slice(common_prefix.len(), column.len()).to_owned()
+    };
+    wr.write_line(fmt!("# prefix: %s", common_prefix));
+    wr.write_line(str::connect(header, "\t"));
+    for rows.each |row| {
+        wr.write_line(*row);
+    }
+    Ok(())
+}
+
+/// Merges readers that are each sorted by `key_label` into `wr`, keeping
+/// the merged output sorted. Keys compare numerically when both parse as
+/// numbers, so `ts:9` comes before `ts:10`, and as strings otherwise.
+pub fn merge_sorted_ltsv<W: io::Writer>(readers: ~[@io::Reader], wr: &W, key_label: &str)
+                                        -> Result<uint, ~str> {
+    let key = key_label.to_owned();
+    let parsers = do readers.map |rd| { LTSVParser::new(rd) };
+    // index of each reader's head record within that reader.
+    let mut positions = vec::from_elem(parsers.len(), 0u);
+    let mut heads = ~[];
+    for parsers.eachi |i, parser| {
+        match parser.next_record() {
+            Ok(record) => heads.push(record),
+            Err(reason) => return Err(fmt!("reader %u: record 0: %s", i, reason))
+        }
+    }
+    let mut n = 0u;
+    loop {
+        // pick the smallest key; ties go to the earliest reader.
+        let mut min: Option<uint> = None;
+        for heads.eachi |i, head| {
+            match *head {
+                Some(ref record) => {
+                    let k = match record.find(&key) {
+                        Some(k) => k,
+                        None => {
+                            return Err(fmt!("reader %u: %s", i,
+                                            missing_label(key_label, positions[i])));
+                        }
+                    };
+                    match min {
+                        Some(j) if key_le(*heads[j].get_ref().get(&key), *k) => (),
+                        _ => min = Some(i)
+                    }
+                }
+                None => ()
+            }
+        }
+        let i = match min {
+            Some(i) => i,
+            None => break
+        };
+        positions[i] += 1;
+        let next = match parsers[i].next_record() {
+            Ok(record) => record,
+            Err(reason) => return Err(fmt!("reader %u: record %u: %s", i, positions[i], reason))
+        };
+        let record = util::replace(&mut heads[i], next).unwrap();
+        wr.write_ltsv_record(&record);
+        wr.write_char('\n');
+        n += 1;
+    }
+    Ok(n)
+}
+
+fn key_le(a: &str, b: &str) -> bool {
+    match (from_str::from_str::<f64>(a), from_str::from_str::<f64>(b)) {
+        (Some(x), Some(y)) => x <= y,
+        _ => a <= b
+    }
+}
+
+/// Writes `records` followed by `footer` as the final record, marked with
+/// an added `_footer:1` field; `is_footer_record` recognizes it on reading.
+/// It is an error for a data record to carry the `_footer` label.
+pub fn write_ltsv_with_footer<W: io::Writer>(wr: &W, records: &[Record], footer: &Record)
+                                             -> Result<(), ~str> {
+    let marker = ~"_footer";
+    for records.eachi |i, record| {
+        match check_record(record) {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+        if record.contains_key(&marker) {
+            return Err(fmt!("record %u: already has label %s", i, marker));
+        }
+    }
+    let mut footer = copy *footer;
+    footer.insert(marker, ~"1");
+    match check_record(&footer) {
+        Err(reason) => return Err(reason),
+        Ok(()) => ()
+    }
+    wr.write_ltsv(records);
+    wr.write_ltsv_record(&footer);
+    wr.write_char('\n');
+    Ok(())
+}
+
+pub fn is_footer_record(record: &Record) -> bool {
+    record.find(&~"_footer") == Some(&~"1")
+}
+
+pub fn record_to_combined_log(record: &Record) -> Result<~str, ~str> {
+    let mut required = ~[];
+    for [~"host", ~"time", ~"req", ~"status", ~"size"].each |label| {
+        match record.find(label) {
+            Some(value) => required.push(copy *value),
+            None => return Err(fmt!("missing label %s", *label))
+        }
+    }
+    let time = if required[1].starts_with("[") {
+        copy required[1]
+    } else {
+        fmt!("[%s]", required[1])
+    };
+    Ok(fmt!("%s %s %s %s \"%s\" %s %s \"%s\" \"%s\"",
+            required[0], value_or_dash(record, "ident"), value_or_dash(record, "user"),
+            time, required[2], required[3], required[4],
+            value_or_dash(record, "referer"), value_or_dash(record, "ua")))
+}
+
+/// Parses a common or combined access log line. `time` keeps its brackets,
+/// quotes around `req`, `referer` and `ua` are removed but escapes inside
+/// them are kept as-is, and `ident`/`user` are only set when not `-`.
+pub fn record_from_combined_log(line: &str) -> Result<Record, ~str> {
+    let line = str::trim_right(line);
+    let mut fields = ~[];
+    let mut pos = 0u;
+    while pos < line.len() {
+        match access_log_field(line, pos) {
+            Some((field, next)) => { fields.push(field); pos = next; }
+            None => return Err(fmt!("malformed access log line: %s", line))
+        }
+    }
+    if (fields.len() != 7 && fields.len() != 9) || !fields[3].starts_with("[")
+        || from_str::from_str::<uint>(fields[5]).is_none() {
+        return Err(fmt!("malformed access log line: %s", line));
+    }
+    let mut record = LinearMap::new();
+    record.insert(~"host", copy fields[0]);
+    if fields[1] != ~"-" { record.insert(~"ident", copy fields[1]); }
+    if fields[2] != ~"-" { record.insert(~"user", copy fields[2]); }
+    record.insert(~"time", copy fields[3]);
+    record.insert(~"req", copy fields[4]);
+    record.insert(~"status", copy fields[5]);
+    record.insert(~"size", copy fields[6]);
+    if fields.len() == 9 {
+        record.insert(~"referer", copy fields[7]);
+        record.insert(~"ua", copy fields[8]);
+    }
+    Ok(record)
+}
+
+pub fn write_ltsv_renamed<W: io::Writer>(wr: &W, records: &[Record], renames: &[(~str, ~str)])
+                                         -> Result<(), ~str> {
+    for renames.each |&(_, ref to)| {
+        if !is_valid_label(*to) {
+            return Err(fmt!("invalid label: %s", *to));
+        }
+    }
+    let mut renamed = ~[];
+    for records.eachi |i, record| {
+        let mut out = LinearMap::new();
+        for record.each |&(label, value)| {
+            let label = match renames.find(|&(ref from, _)| *from == *label) {
+                Some((_, to)) => to,
+                None => copy *label
+            };
+            if out.contains_key(&label) {
+                return Err(fmt!("record %u: duplicate label %s after renaming", i, label));
+            }
+            out.insert(label, copy *value);
+        }
+        match check_record(&out) {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+        renamed.push(out);
+    }
+    wr.write_ltsv(renamed);
+    Ok(())
+}
+
+pub fn write_ltsv_with_manifest<W: io::Writer, M: io::Writer>(wr: &W, manifest: &M,
+                                                              records: &[Record])
+                                                              -> Result<(), ~str> {
+    for records.each |record| {
+        match check_record(record) {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+    }
+    for records.each |record| {
+        wr.write_ltsv_record(record);
+        wr.write_char('\n');
+        manifest.write_line(record_hash(record));
+    }
+    Ok(())
+}
+
+/// Writes `records` in a binary format that `read_ltsv_binary` reads back.
+/// It starts with the magic `LTSB` and a version byte (1). Each record is
+/// then a field count followed by, for each field in label order, the label
+/// length, the label, the value length and the value. Counts and lengths
+/// are little-endian u32. Values may contain any bytes, including TABs and
+/// newlines; labels must still be valid LTSV labels.
+pub fn write_ltsv_binary<W: io::Writer>(wr: &W, records: &[Record]) -> Result<(), ~str> {
+    for records.eachi |i, record| {
+        for record.each_key |label| {
+            if !is_valid_label(*label) {
+                return Err(fmt!("record %u: invalid label: %s", i, *label));
+            }
+        }
+    }
+    wr.write_str("LTSB");
+    wr.write_u8(1);
+    for records.each |record| {
+        wr.write_le_u32(record.len() as u32);
+        for sorted_labels(record).each |label| {
+            let value = record.get(label);
+            wr.write_le_u32(label.len() as u32);
+            wr.write_str(*label);
+            wr.write_le_u32(value.len() as u32);
+            wr.write_str(*value);
+        }
+    }
+    Ok(())
+}
+
+pub fn read_ltsv_binary<R: io::Reader>(rd: &R) -> Result<~[Record], ~str> {
+    if rd.read_bytes(4) != str::to_bytes("LTSB") {
+        return Err(~"not an LTSV binary stream");
+    }
+    let version = rd.read_bytes(1);
+    if version.is_empty() {
+        return Err(~"truncated LTSV binary stream");
+    }
+    if version[0] != 1 {
+        return Err(fmt!("unsupported LTSV binary version: %u", version[0] as uint));
+    }
+    let mut records = ~[];
+    loop {
+        let header = rd.read_bytes(4);
+        if header.is_empty() { break; }
+        if header.len() < 4 {
+            return Err(~"truncated LTSV binary stream");
+        }
+        let mut record = LinearMap::new();
+        for (le_u32(header) as uint).times {
+            let label = match read_binary_string(rd) {
+                Ok(label) => label,
+                Err(reason) => return Err(reason)
+            };
+            let value = match read_binary_string(rd) {
+                Ok(value) => value,
+                Err(reason) => return Err(reason)
+            };
+            if !is_valid_label(label) {
+                return Err(fmt!("record %u: invalid label: %s", records.len(), label));
+            }
+            record.insert(label, value);
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Writes `records`, and after every `every` of them a record holding only
+/// `label`, whose value is a checksum of all data records written so far.
+/// `verify_periodic_checksum` checks such a stream.
+pub fn write_ltsv_with_periodic_checksum<W: io::Writer>(wr: &W, records: &[Record], every: uint,
+                                                        label: &str) -> Result<(), ~str> {
+    if every == 0 {
+        return Err(~"every must be positive");
+    }
+    if !is_valid_label(label) {
+        return Err(fmt!("invalid label: %s", label));
+    }
+    let key = label.to_owned();
+    for records.eachi |i, record| {
+        match check_record(record) {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+        if record.contains_key(&key) {
+            return Err(fmt!("record %u: already has label %s", i, label));
+        }
+    }
+    let mut h = FNV_OFFSET_BASIS;
+    for records.eachi |i, record| {
+        wr.write_ltsv_record(record);
+        wr.write_char('\n');
+        h = running_checksum(h, record);
+        if (i + 1) % every == 0 {
+            wr.write_str(fmt!("%s:%s\n", label, hex_u64(h)));
+        }
+    }
+    Ok(())
+}
+
+/// Emits one record per measure label present in `record`, holding the
+/// present `id_labels` plus `variable` (the measure label) and `value`.
+/// Measures missing from `record` are skipped.
+pub fn unpivot(record: &Record, id_labels: &[~str], measure_labels: &[~str]) -> ~[Record] {
+    let mut long = ~[];
+    for measure_labels.each |measure| {
+        if !record.contains_key(measure) { loop; }
+        let mut out = LinearMap::new();
+        for id_labels.each |label| {
+            match record.find(label) {
+                Some(id) => { out.insert(copy *label, copy *id); }
+                None => ()
+            }
+        }
+        out.insert(~"variable", copy *measure);
+        out.insert(~"value", copy *record.get(measure));
+        long.push(out);
+    }
+    long
+}
+
+/// Writes `records` with the values of `encode_labels` percent-encoded:
+/// every byte other than `[0-9A-Za-z_.~-]` becomes `%XX`. Set
+/// `ParseOptions::percent_decode_labels` to the same labels to read them back.
+pub fn write_ltsv_encoded<W: io::Writer>(wr: &W, records: &[Record], encode_labels: &[~str])
+                                         -> Result<(), ~str> {
+    let mut encoded = ~[];
+    for records.each |record| {
+        let mut out = LinearMap::new();
+        for record.each |&(label, value)| {
+            let value = if encode_labels.contains(label) {
+                percent_encode(*value)
+            } else {
+                copy *value
+            };
+            out.insert(copy *label, value);
+        }
+        match check_record(&out) {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+        encoded.push(out);
+    }
+    wr.write_ltsv(encoded);
+    Ok(())
+}
+
+/// Copies the records of `rd` to `wr`, at most `max_per_sec` per second,
+/// and returns how many were copied. Record `n` is not written before
+/// `n / max_per_sec` seconds after the first; because the schedule is kept
+/// from the start rather than between records, time spent reading and
+/// writing does not add up, and a slow stretch is followed by a burst of
+/// no more than the cap.
+pub fn throttle_ltsv<R: io::Reader, W: io::Writer>(rd: &R, wr: &W, max_per_sec: uint)
+                                                   -> Result<uint, ~str> {
+    if max_per_sec == 0 {
+        return Err(~"max_per_sec must be positive");
+    }
+    let iotask = uv::global_loop::get();
+    let start = time::precise_time_ns();
+    let mut n = 0u;
+    let rv = do LTSVParser::new(rd).each_record |record| {
+        let due = start + (n as u64) * 1000000000 / (max_per_sec as u64);
+        let now = time::precise_time_ns();
+        if due > now {
+            let ms = ((due - now) / 1000000) as uint;
+            if ms > 0 { timer::sleep(&iotask, ms); }
+        }
+        wr.write_ltsv_record(&record);
+        wr.write_char('\n');
+        n += 1;
+        true
+    };
+    with_value(rv, n)
+}
+
+/// Writes a header record mapping each label of `schema` to its type name
+/// (`int`, `float`, `bool` or `string`), then `records`. Every value of a
+/// label in `schema` must match its type, and `schema` must not be empty,
+/// since an empty header could not be told apart from the first record.
+pub fn write_ltsv_typed<W: io::Writer>(wr: &W, schema: &[(~str, ValueType)], records: &[Record])
+                                       -> Result<(), ~str> {
+    if schema.is_empty() {
+        return Err(~"schema is empty");
+    }
+    for schema.each |&(ref label, _)| {
+        if !is_valid_label(*label) {
+            return Err(fmt!("invalid label: %s", *label));
+        }
+    }
+    for records.eachi |i, record| {
+        match check_record(record) {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+        for schema.each |&(ref label, ty)| {
+            match record.find(label) {
+                Some(value) if !value_matches(*value, ty) => {
+                    return Err(fmt!("record %u: %s is not %s: %s",
+                                    i, *label, type_name(ty), *value));
+                }
+                _ => ()
+            }
+        }
+    }
+    let header = do schema.map |&(ref label, ty)| { fmt!("%s:%s", *label, type_name(ty)) };
+    wr.write_str(str::connect(header, "\t"));
+    wr.write_char('\n');
+    wr.write_ltsv(records);
+    Ok(())
+}
+
+/// Reads a stream written by `write_ltsv_typed`, returning the schema,
+/// sorted by label, and the records, whose values are checked against it.
+pub fn read_ltsv_typed<R: io::Reader>(rd: &R) -> Result<(~[(~str, ValueType)], ~[Record]), ~str> {
+    let parser = LTSVParser::new(rd);
+    let header = match parser.next_record() {
+        Ok(Some(header)) => header,
+        Ok(None) => return Err(~"missing schema header"),
+        Err(reason) => return Err(reason)
+    };
+    let mut schema = ~[];
+    for sorted_labels(&header).each |label| {
+        match type_from_name(*header.get(label)) {
+            Some(ty) => schema.push((copy *label, ty)),
+            None => return Err(fmt!("unknown type for %s: %s", *label, *header.get(label)))
+        }
+    }
+    let mut records = ~[];
+    let rv = do parser.try_each_record |i, record| {
+        let mut rv = Ok(());
+        for schema.each |&(ref label, ty)| {
+            match record.find(label) {
+                Some(value) if !value_matches(*value, ty) => {
+                    rv = Err(fmt!("record %u: %s is not %s: %s",
+                                  i, *label, type_name(ty), *value));
+                    break;
+                }
+                _ => ()
+            }
+        }
+        if rv.is_ok() { records.push(record); }
+        rv
+    };
+    with_value(rv, (schema, records))
+}
+
+pub fn redact_matching<R: io::Reader, W: io::Writer>(rd: &R, wr: &W, matcher: &fn(&str) -> bool,
+                                                     replacement: &str) -> Result<(), ~str> {
+    if !is_valid_value(replacement) {
+        return Err(fmt!("invalid value: %s", replacement));
+    }
+    do LTSVParser::new(rd).try_each_record |_, record| {
+        let mut redacted = LinearMap::new();
+        for record.each |&(label, value)| {
+            let value = if matcher(*value) { replacement.to_owned() } else { copy *value };
+            redacted.insert(copy *label, value);
+        }
+        wr.write_ltsv_record(&redacted);
+        wr.write_char('\n');
+        Ok(())
+    }
+}
+
+pub fn split_ltsv<R: io::Reader, A: io::Writer, B: io::Writer>(rd: &R, train: &A, test: &B,
+                                                               train_ratio: f64, seed: u64)
+                                                               -> Result<(uint, uint), ~str> {
+    if train_ratio < 0.0 || train_ratio > 1.0 {
+        return Err(~"train_ratio must be between 0 and 1");
+    }
+    let mut rng = Rng64::new(seed);
+    let (mut ntrain, mut ntest) = (0u, 0u);
+    let rv = do LTSVParser::new(rd).each_record |record| {
+        if rng.next_f64() < train_ratio {
+            train.write_ltsv_record(&record);
+            train.write_char('\n');
+            ntrain += 1;
+        } else {
+            test.write_ltsv_record(&record);
+            test.write_char('\n');
+            ntest += 1;
+        }
+        true
+    };
+    with_value(rv, (ntrain, ntest))
+}
+
+/// Writes `records` delta-encoded: the first record is written in full and
+/// each later one as only the fields that are new or changed since the
+/// record before it, plus a `_delta` field listing the labels it no longer
+/// has, separated by commas (empty if none). A record without `_delta` is
+/// always a full record. `read_ltsv_delta` reverses this.
+pub fn write_ltsv_delta<W: io::Writer>(wr: &W, records: &[Record]) -> Result<(), ~str> {
+    let marker = ~"_delta";
+    for records.eachi |i, record| {
+        match check_record(record) {
+            Err(reason) => return Err(reason),
+            Ok(()) => ()
+        }
+        if record.contains_key(&marker) {
+            return Err(fmt!("record %u: already has label %s", i, marker));
+        }
+    }
+    for records.eachi |i, record| {
+        if i == 0 {
+            wr.write_ltsv_record(record);
+            wr.write_char('\n');
+            loop;
+        }
+        let prev = &records[i - 1];
+        let mut delta = LinearMap::new();
+        for record.each |&(label, value)| {
+            if prev.find(label) != Some(value) {
+                delta.insert(copy *label, copy *value);
+            }
+        }
+        let removed = sorted_labels(prev).filtered(|label| !record.contains_key(label));
+        delta.insert(copy marker, str::connect(removed, ","));
+        wr.write_ltsv_record(&delta);
+        wr.write_char('\n');
+    }
+    Ok(())
+}
+
+/// Reads records written by `write_ltsv_delta` (see there for the format).
+/// A `_delta` record with no record before it, or one listing an empty or
+/// invalid label, is an error.
+pub fn read_ltsv_delta<R: io::Reader>(rd: &R) -> Result<~[Record], ~str> {
+    let marker = ~"_delta";
+    let mut records: ~[Record] = ~[];
+    let rv = do LTSVParser::new(rd).try_each_record |i, record| {
+        let mut delta = record;
+        match delta.pop(&marker) {
+            None => { records.push(delta); Ok(()) }
+            Some(_) if records.is_empty() => {
+                Err(fmt!("record %u: delta without a previous record", i))
+            }
+            Some(removed) => {
+                let labels = if removed.is_empty() { ~[] } else { str::split_char(removed, ',') };
+                match labels.find(|label| !is_valid_label(*label)) {
+                    Some(label) => {
+                        return Err(fmt!("record %u: invalid label in %s: \"%s\"", i, marker, label));
+                    }
+                    None => ()
+                }
+                let mut full = copy *records.last();
+                for labels.each |label| {
+                    full.remove(label);
+                }
+                for delta.each |&(label, value)| {
+                    full.insert(copy *label, copy *value);
+                }
+                records.push(full);
+                Ok(())
+            }
+        }
+    };
+    with_value(rv, records)
+}
+
+// turns the outcome of a fallible walk into `value` once it succeeds.
+fn with_value<T>(rv: Result<(), ~str>, value: T) -> Result<T, ~str> {
+    match rv {
+        Ok(()) => Ok(value),
+        Err(reason) => Err(reason)
+    }
+}
+
+fn missing_label(label: &str, index: uint) -> ~str {
+    fmt!("record %u: missing label %s", index, label)
+}
+
+fn numeric_value(record: &Record, label: &str, index: uint) -> Result<f64, ~str> {
+    match record.find(&label.to_owned()) {
+        Some(value) => match from_str::from_str(*value) {
+            Some(v) => Ok(v),
+            None => Err(fmt!("record %u: %s is not a number: %s", index, label, *value))
+        },
+        None => Err(missing_label(label, index))
+    }
+}
+
+// P² quantile estimator (Jain & Chlamtac, 1985): tracks five markers whose
+// heights approximate the minimum, p/2, p, (1+p)/2 quantiles and maximum.
+struct P2Estimator {
+    p: f64,
+    count: uint,
+    heights: [f64, ..5],
+    positions: [f64, ..5],
+    desired: [f64, ..5],
+    increments: [f64, ..5]
+}
+
+impl P2Estimator {
+    fn new(p: f64) -> P2Estimator {
+        P2Estimator {
+            p: p,
+            count: 0,
+            heights: [0.0, ..5],
+            positions: [1.0, 2.0, 3.0, 4.0, 5.0],
+            desired: [1.0, 1.0 + 2.0 * p, 1.0 + 4.0 * p, 3.0 + 2.0 * p, 5.0],
+            increments: [0.0, p / 2.0, p, (1.0 + p) / 2.0, 1.0]
+        }
+    }
+
+    fn add(&mut self, x: f64) {
+        if self.count < 5 {
+            self.heights[self.count] = x;
+            self.count += 1;
+            if self.count == 5 {
+                sort::quick_sort3(self.heights);
+            }
+            return;
+        }
+        self.count += 1;
+        let k = if x < self.heights[0] {
+            self.heights[0] = x;
+            0
+        } else if x >= self.heights[4] {
+            self.heights[4] = x;
+            3
+        } else {
+            let mut k = 0;
+            while x >= self.heights[k + 1] { k += 1; }
+            k
+        };
+        for uint::range(k + 1, 5) |i| {
+            self.positions[i] += 1.0;
+        }
+        for uint::range(0, 5) |i| {
+            self.desired[i] += self.increments[i];
+        }
+        for uint::range(1, 4) |i| {
+            let d = self.desired[i] - self.positions[i];
+            if (d >= 1.0 && self.positions[i + 1] - self.positions[i] > 1.0) ||
+               (d <= -1.0 && self.positions[i - 1] - self.positions[i] < -1.0) {
+                let d = if d > 0.0 { 1.0 } else { -1.0 };
+                let q = self.parabolic(i, d);
+                self.heights[i] = if self.heights[i - 1] < q && q < self.heights[i + 1] {
+                    q
+                } else {
+                    self.linear(i, d)
+                };
+                self.positions[i] += d;
+            }
+        }
+    }
+
+    fn parabolic(&self, i: uint, d: f64) -> f64 {
+        let (q, n) = (&self.heights, &self.positions);
+        q[i] + d / (n[i + 1] - n[i - 1]) *
+            ((n[i] - n[i - 1] + d) * (q[i + 1] - q[i]) / (n[i + 1] - n[i]) +
+             (n[i + 1] - n[i] - d) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]))
+    }
+
+    fn linear(&self, i: uint, d: f64) -> f64 {
+        let j = if d > 0.0 { i + 1 } else { i - 1 };
+        self.heights[i] + d * (self.heights[j] - self.heights[i]) /
+            (self.positions[j] - self.positions[i])
+    }
+
+    fn estimate(&self) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        if self.count < 5 {
+            // too few observations for the markers; use the exact quantile.
+            let mut values = vec::from_slice(self.heights.slice(0, self.count));
+            sort::quick_sort3(values);
+            let i = (self.p * ((self.count - 1) as f64) + 0.5) as uint;
+            return Some(values[i]);
+        }
+        Some(self.heights[2])
+    }
+}
+
+// xorshift64* seeded through splitmix64, so that any seed (including 0)
+// gives a well-mixed state and the sequence is identical on every platform.
+struct Rng64 {
+    state: u64
+}
+
+impl Rng64 {
+    fn new(seed: u64) -> Rng64 {
+        let mut z = seed + 0x9e3779b97f4a7c15;
+        z = (z ^ (z >> 30)) * 0xbf58476d1ce4e5b9;
+        z = (z ^ (z >> 27)) * 0x94d049bb133111eb;
+        z = z ^ (z >> 31);
+        Rng64 { state: if z == 0 { 0x9e3779b97f4a7c15 } else { z } }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state * 0x2545f4914f6cdd1d
+    }
+
+    // uniform in [0, n).
+    fn next_below(&mut self, n: uint) -> uint {
+        (self.next_u64() % (n as u64)) as uint
+    }
+
+    // uniform in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        ((self.next_u64() >> 11) as f64) / 9007199254740992.0
+    }
+}
+
+fn value_or_dash(record: &Record, label: &str) -> ~str {
+    match record.find(&label.to_owned()) {
+        Some(value) => copy *value,
+        None => ~"-"
+    }
+}
+
+// returns the field starting at `start` and the position of the next one.
+fn access_log_field(line: &str, start: uint) -> Option<(~str, uint)> {
+    let len = line.len();
+    let mut end = start;
+    match line[start] as char {
+        '"' => {
+            end += 1;
+            while end < len && line[end] as char != '"' {
+                if line[end] as char == '\\' { end += 1; }
+                end += 1;
+            }
+            if end >= len { return None; }
+            end += 1;
+        }
+        '[' => match str::find_char_from(line, ']', start) {
+            Some(i) => end = i + 1,
+            None => return None
+        },
+        _ => while end < len && line[end] as char != ' ' { end += 1; }
+    }
+    if end < len && line[end] as char != ' ' { return None; }
+    let field = if line[start] as char == '"' {
+        line.slice(start + 1, end - 1).to_owned()
+    } else {
+        line.slice(start, end).to_owned()
+    };
+    Some((field, if end < len { end + 1 } else { end }))
+}
+
+static BINARY_READ_CHUNK: uint = 65536;
+
+fn read_binary_string<R: io::Reader>(rd: &R) -> Result<~str, ~str> {
+    let len = rd.read_bytes(4);
+    if len.len() < 4 {
+        return Err(~"truncated LTSV binary stream");
+    }
+    // the length is untrusted, so only allocate as the bytes actually arrive.
+    let len = le_u32(len) as uint;
+    let mut bytes = ~[];
+    while bytes.len() < len {
+        let chunk = rd.read_bytes(uint::min(len - bytes.len(), BINARY_READ_CHUNK));
+        if chunk.is_empty() {
+            return Err(~"truncated LTSV binary stream");
+        }
+        bytes.push_all(chunk);
+    }
+    if !str::is_utf8(bytes) {
+        return Err(~"string is not valid UTF-8");
+    }
+    Ok(str::from_bytes(bytes))
+}
+
+fn running_checksum(h: u64, record: &Record) -> u64 {
+    fnv1a(fnv1a(h, canonical_string(record)), "\n")
+}
+
+// splits [min, max] into `buckets` equal bins; the last one includes max.
+fn histogram_counts(values: &[f64], buckets: uint) -> (f64, f64, ~[uint]) {
+    let min = values.foldl(values[0], |a, b| f64::min(*a, *b));
+    let max = values.foldl(values[0], |a, b| f64::max(*a, *b));
+    let mut counts = vec::from_elem(buckets, 0u);
+    for values.each |&v| {
+        let i = if max == min {
+            0
+        } else {
+            uint::min(((v - min) / (max - min) * (buckets as f64)) as uint, buckets - 1)
+        };
+        counts[i] += 1;
+    }
+    (min, max, counts)
+}
+
+fn percent_encode(value: &str) -> ~str {
+    let digits = "0123456789ABCDEF";
+    let mut rv = ~"";
+    for str::each(value) |b| {
+        match b as char {
+            '0'..'9' | 'A'..'Z' | 'a'..'z' | '_' | '.' | '~' | '-' => {
+                str::push_char(&mut rv, b as char)
+            }
+            _ => {
+                str::push_char(&mut rv, '%');
+                str::push_char(&mut rv, digits[(b >> 4) as uint] as char);
+                str::push_char(&mut rv, digits[(b & 0xf) as uint] as char);
+            }
+        }
+    }
+    rv
+}
+
+fn percent_decode(value: &str) -> Result<~str, ~str> {
+    let mut bytes = ~[];
+    let mut i = 0u;
+    while i < value.len() {
+        if value[i] != '%' as u8 {
+            bytes.push(value[i]);
+            i += 1;
+            loop;
+        }
+        let byte = if i + 3 <= value.len() {
+            uint::parse_bytes(str::to_bytes(value.slice(i + 1, i + 3)), 16)
+        } else {
+            None
+        };
+        match byte {
+            Some(b) => bytes.push(b as u8),
+            None => return Err(fmt!("invalid percent-encoding: %s", value))
+        }
+        i += 3;
+    }
+    if !str::is_utf8(bytes) {
+        return Err(fmt!("percent-decoded value is not valid UTF-8: %s", value));
+    }
+    Ok(str::from_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::io::WriterUtil;
+    use std::{tempfile, time};
+
+    fn mk_record(fields: &[(&str, &str)]) -> Record {
+        let mut record = LinearMap::new();
+        for fields.each |&(label, value)| {
+            record.insert(label.to_owned(), value.to_owned());
+        }
+        record
+    }
+
+    fn mk_record_string() -> ~str {
+        do io::with_str_writer |wr| {
+            // genzairyou
+            wr.write_str(fmt!("%s:%s\t", "tofu", "豆"));
+            wr.write_str(fmt!("%s:%s\t", "kamaboko", "魚"));
+            wr.write_str(fmt!("%s:%s\n", "sukonbu", "海藻"));
+            // konomi
+            wr.write_str(fmt!("%s:%s\t", "tofu", "好き"));
+            wr.write_str(fmt!("%s:%s\t", "kamaboko", "普通"));
+            wr.write_str(fmt!("%s:%s\n", "sukonbu", "苦手"));
+        }
+    }
+
+    #[test]
+    fn test_parse_simple() {
+        let records = io::with_str_reader(~"a:1\tb:2", |rd| rd.read_ltsv());
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_full() {
+        let s = mk_record_string();
+        let records = io::with_str_reader(s, |rd| rd.read_ltsv());
+        assert_eq!(records.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_ltsv_trailing_nl_and_write() {
+        let s = mk_record_string();
+        let records_1 = io::with_str_reader(s, |rd| rd.read_ltsv());
+        let s2 = io::with_str_writer(|wr| wr.write_ltsv(records_1));
+        let records_2 = io::with_str_reader(s2, |rd| rd.read_ltsv());
+        assert_eq!(records_1, records_2);
+    }
+
+    #[test]
+    fn test_each_read_each_record() {
+        let s = mk_record_string();
+        let ks = [~"tofu", ~"kamaboko", ~"sukonbu"];
+        let vs = [~"豆", ~"魚", ~"海藻", ~"好き", ~"普通", ~"苦手"];
+        do io::with_str_reader(s) |rd| {
+            for rd.each_ltsv_record |record| {
+                for record.each |&(k, v)| {
+                    assert!(ks.contains(k));
+                    assert!(vs.contains(v));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_parsed_locale() {
+        let record = mk_record([("pi", "3,14")]);
+        let v: Option<f64> = get_parsed_locale(&record, "pi", true);
+        assert_eq!(v, Some(3.14));
+        let v: Option<f64> = get_parsed_locale(&record, "pi", false);
+        assert_eq!(v, None);
+        let v: Option<f64> = get_parsed_locale(&record, "e", true);
+        assert_eq!(v, None);
+    }
+
+    #[test]
+    fn test_changed_records() {
+        let old = ~"id:1\tv:a\nid:2\tv:b\n";
+        let new = ~"id:1\tv:a\nid:2\tv:B\nid:3\tv:c\n";
+        let changed = do io::with_str_reader(old) |old| {
+            io::with_str_reader(new, |new| changed_records(old, new, "id"))
+        };
+        assert_eq!(changed, Ok(~[mk_record([("id", "2"), ("v", "B")]),
+                                 mk_record([("id", "3"), ("v", "c")])]));
+    }
+
+    #[test]
+    fn test_write_ltsv_numbered() {
+        let records = ~[mk_record([("a", "x")]), mk_record([("a", "y"), ("_line", "9")])];
+        let s = io::with_str_writer(|wr| write_ltsv_numbered(wr, records, "_line"));
+        let records = io::with_str_reader(s, |rd| rd.read_ltsv());
+        assert_eq!(records, ~[mk_record([("a", "x"), ("_line", "1")]),
+                              mk_record([("a", "y"), ("_line", "2")])]);
+    }
+
+    #[test]
+    fn test_seek_to_label() {
+        do io::with_str_reader("a:1\tb:2\tc:3\na:4\tc:5\n") |rd| {
+            let parser = LTSVParser::new(rd);
+            assert_eq!(parser.seek_to_label("b"), Ok(Some(~"2")));
+            assert_eq!(parser.seek_to_label("c"), Ok(Some(~"3")));
+            assert_eq!(parser.seek_to_label("b"), Ok(None));
+            assert_eq!(parser.seek_to_label("b"), Ok(None));
+        }
+    }
+
+    #[test]
+    fn test_read_ltsv_from_json_field() {
+        let s = ~"{\"log\":\"host:127.0.0.1\\tstatus:200\",\"stream\":\"stdout\"}\n" +
+                "{\"log\":\"host:127.0.0.2\\tstatus:404\"}\n";
+        let records = io::with_str_reader(s, |rd| read_ltsv_from_json_field(rd, "log"));
+        assert_eq!(records, Ok(~[mk_record([("host", "127.0.0.1"), ("status", "200")]),
+                                 mk_record([("host", "127.0.0.2"), ("status", "404")])]));
+        let records = io::with_str_reader(s, |rd| read_ltsv_from_json_field(rd, "msg"));
+        assert!(records.is_err());
+    }
+
+    #[test]
+    fn test_check_monotonic() {
+        let check = |s: ~str, strictly: bool| {
+            io::with_str_reader(s, |rd| rd.check_monotonic("seq", strictly))
+        };
+        assert_eq!(check(~"seq:1\nseq:2\nseq:3\n", true), Ok(()));
+        assert_eq!(check(~"seq:1\nseq:1\nseq:2\n", false), Ok(()));
+        assert_eq!(check(~"seq:1\nseq:1\nseq:2\n", true),
+                   Err(~"record 1: seq is not monotonic"));
+        assert_eq!(check(~"seq:1\nseq:3\nseq:2\n", false),
+                   Err(~"record 2: seq is not monotonic"));
+        assert!(check(~"seq:1\nid:2\n", false).is_err());
+    }
+
+    #[test]
+    fn test_record_from_kv_args() {
+        let record = record_from_kv_args([~"host=a", ~"status=200", ~"q=x=y"], '=');
+        assert_eq!(record, Ok(mk_record([("host", "a"), ("status", "200"), ("q", "x=y")])));
+        assert!(record_from_kv_args([~"host"], '=').is_err());
+        assert!(record_from_kv_args([~"=a"], '=').is_err());
+        assert!(record_from_kv_args([~"ho st=a"], '=').is_err());
+    }
+
+    #[test]
+    fn test_write_fixed_width() {
+        let records = ~[mk_record([("host", "127.0.0.1"), ("status", "200")]),
+                        mk_record([("host", "localhost"), ("status", "404")])];
+        let columns = [(~"host", 6u), (~"status", 4u), (~"size", 2u)];
+        let s = io::with_str_writer(|wr| write_fixed_width(wr, records, columns));
+        assert_eq!(s, ~"127.0.200   \nlocalh404   \n");
+    }
+
+    #[test]
+    fn test_replay_ltsv() {
+        let s = ~"ts:0\ta:1\nts:1\ta:2\nts:2\ta:3\n";
+        let out = do io::with_str_writer |wr| {
+            let rv = io::with_str_reader(s, |rd| replay_ltsv(rd, wr, "ts", 0.001));
+            assert_eq!(rv, Ok(()));
+        };
+        assert_eq!(io::with_str_reader(out, |rd| rd.read_ltsv()),
+                   io::with_str_reader(s, |rd| rd.read_ltsv()));
+    }
+
+    #[test]
+    fn test_coalesce_by_key() {
+        let s = ~"id:1\ta:x\nid:2\ta:y\nid:1\ta:z\tb:w\n";
+        let records = io::with_str_reader(s, |rd| rd.coalesce_by_key("id", KeepFirst));
+        assert_eq!(records, Ok(~[mk_record([("id", "1"), ("a", "x"), ("b", "w")]),
+                                 mk_record([("id", "2"), ("a", "y")])]));
+        let records = io::with_str_reader(s, |rd| rd.coalesce_by_key("id", KeepLast));
+        assert_eq!(records, Ok(~[mk_record([("id", "1"), ("a", "z"), ("b", "w")]),
+                                 mk_record([("id", "2"), ("a", "y")])]));
+    }
+
+    #[test]
+    fn test_normalize_eol_ltsv() {
+        let mut n = Ok(0);
+        let s = do io::with_str_writer |wr| {
+            n = io::with_str_reader("a:1\r\na:2\na:3\r\n", |rd| normalize_eol_ltsv(rd, wr));
+        };
+        assert_eq!(n, Ok(3));
+        assert_eq!(s, ~"a:1\na:2\na:3\n");
+    }
+
+    #[test]
+    fn test_bucket_by_time() {
+        let s = ~"ts:100\ta:1\nts:159\ta:2\nts:160\ta:3\nts:x\ta:4\n";
+        let sentinel = Some(u64::max_value);
+        let buckets = io::with_str_reader(s, |rd| rd.bucket_by_time("ts", 60, sentinel)).unwrap();
+        assert_eq!(buckets.len(), 3);
+        assert_eq!(buckets.find(&1).unwrap().len(), 2);
+        assert_eq!(buckets.find(&2).unwrap().len(), 1);
+        assert_eq!(*buckets.find(&u64::max_value).unwrap(),
+                   ~[mk_record([("ts", "x"), ("a", "4")])]);
+        let buckets = io::with_str_reader(s, |rd| rd.bucket_by_time("ts", 60, None));
+        assert_eq!(buckets.get_err(), ~"record 3: missing or invalid ts");
+        let buckets = io::with_str_reader(s, |rd| rd.bucket_by_time("ts", 60, Some(2)));
+        assert_eq!(buckets.get_err(), ~"record 2: ts falls into the sentinel bucket");
+    }
+
+    #[test]
+    fn test_writer_output_is_valid_ltsv() {
+        let records = ~[mk_record([("host", "127.0.0.1"), ("ua", "Mozilla/5.0 (X11)")]),
+                        mk_record([("empty", ""), ("a.b-c_D9", "値:with:colons")]),
+                        mk_record([])];
+        let bytes = io::with_bytes_writer(|wr| wr.write_ltsv(records));
+        assert_eq!(assert_valid_ltsv_bytes(bytes), Ok(()));
+        let s = mk_record_string();
+        let records = io::with_str_reader(s, |rd| rd.read_ltsv());
+        let bytes = io::with_bytes_writer(|wr| wr.write_ltsv(records));
+        assert_eq!(assert_valid_ltsv_bytes(bytes), Ok(()));
+        assert!(assert_valid_ltsv_bytes(str::to_bytes("a:1\r\n")).is_err());
+        assert!(assert_valid_ltsv_bytes(str::to_bytes("a b:1\n")).is_err());
+        assert!(assert_valid_ltsv_bytes(str::to_bytes("a:1\t\n")).is_err());
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_write_invalid_label() {
+        let record = mk_record([("a b", "1")]);
+        io::with_str_writer(|wr| wr.write_ltsv_record(&record));
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_write_invalid_value() {
+        let record = mk_record([("a", "1\t2")]);
+        io::with_str_writer(|wr| wr.write_ltsv_record(&record));
+    }
+
+    #[test]
+    fn test_try_write_ltsv_record() {
+        let mut rv = Ok(());
+        let bad = mk_record([("a b", "1")]);
+        let s = io::with_str_writer(|wr| rv = wr.try_write_ltsv_record(&bad));
+        assert_eq!(rv, Err(~"invalid label: a b"));
+        assert_eq!(s, ~"");
+        let good = mk_record([("a", "1")]);
+        let s = io::with_str_writer(|wr| rv = wr.try_write_ltsv_record(&good));
+        assert_eq!(rv, Ok(()));
+        assert_eq!(s, ~"a:1");
+    }
+
+    #[test]
+    fn test_write_ltsv_rotating() {
+        let dir = tempfile::mkdtemp(&os::tmpdir(), "ltsv").unwrap();
+        let base = dir.push("out.ltsv");
+        let records = ~[mk_record([("a", "1")]), mk_record([("a", "2")]),
+                        mk_record([("a", "3")])];
+        assert_eq!(write_ltsv_rotating(&base, records, 8), Ok(2));
+        let mut read = ~[];
+        for uint::range(0, 2) |i| {
+            let path = Path(fmt!("%s.%u", base.to_str(), i));
+            let rd = io::file_reader(&path).unwrap();
+            read.push_all_move(rd.read_ltsv());
+            os::remove_file(&path);
+        }
+        os::remove_dir(&dir);
+        assert_eq!(read, records);
+    }
+
+    #[test]
+    fn test_union_labels_ordered() {
+        let s = ~"host:a\tstatus:200\nhost:b\tsize:10\tstatus:404\nua:x\thost:c\n";
+        let labels = io::with_str_reader(s, |rd| rd.union_labels_ordered());
+        assert_eq!(labels, Ok(~[~"host", ~"status", ~"size", ~"ua"]));
+    }
+
+    #[test]
+    fn test_parse_ltsv_chars() {
+        let inputs = [mk_record_string(), ~"a:1\r\nb:2\t c:3\n\n", ~"a:1\tb", ~"a:1\t",
+                      ~":1", ~"a:1\rb:2", ~"a b:1", ~"\n"];
+        for inputs.each |s| {
+            let expected = io::with_str_reader(*s, |rd| LTSVParser::new(rd).read_records());
+            assert_eq!(parse_ltsv_chars(*s), expected);
+        }
+        assert_eq!(parse_ltsv_chars(""), Ok(~[]));
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_parse_ltsv_chars_vs_reader() {
+        let s = str::concat(vec::from_fn(10000, |i| {
+            ~"host:127.0.0.1\tident:-\tuser:frank\ttime:[10/Oct/2000:13:55:36 -0700]\t" +
+                "req:GET /apache_pb.gif HTTP/1.0\t" + fmt!("status:200\tsize:%u\n", i)
+        }));
+        let reader = io::with_str_reader(s, |rd| rd.bench_parse(2, 10));
+        for 2.times { parse_ltsv_chars(s); }
+        let mut total = 0.0;
+        for 10.times {
+            let start = time::precise_time_ns();
+            parse_ltsv_chars(s);
+            total += (time::precise_time_ns() - start) as f64 / 10000.0;
+        }
+        let chars = total / 10.0;
+        io::println(fmt!("reader: %f ns/record, parse_ltsv_chars: %f ns/record, speedup %fx",
+                         reader.mean_ns as float, chars as float,
+                         (reader.mean_ns / chars) as float));
+    }
+
+    #[test]
+    fn test_inline_comment() {
+        let s = ~"a:1\tb:x # y\t# note\na:2\t#\n";
+        let records = do io::with_str_reader(s) |rd| {
+            let opts = ParseOptions { inline_comment: Some('#' as u8), ..ParseOptions::new() };
+            LTSVParser::with_options(rd, opts).read_records()
+        };
+        assert_eq!(records, Ok(~[mk_record([("a", "1"), ("b", "x # y")]),
+                                 mk_record([("a", "2")])]));
+        let records = io::with_str_reader(s, |rd| LTSVParser::new(rd).read_records());
+        assert!(records.is_err());
+    }
+
+    #[test]
+    fn test_summarize_record() {
+        let record = mk_record([("host", "127.0.0.1"), ("status", "200"), ("req", "GET /")]);
+        let labels = [~"status", ~"req", ~"size", ~"host"];
+        assert_eq!(summarize_record(&record, labels, " | "), ~"200 | GET / |  | 127.0.0.1");
+    }
+
+    #[test]
+    fn test_tail_parser() {
+        let dir = tempfile::mkdtemp(&os::tmpdir(), "ltsv").unwrap();
+        let path = dir.push("tail.ltsv");
+        io::file_writer(&path, [io::Create, io::Truncate]).unwrap().write_str("a:1\tb:2\na:3\tb");
+        let mut tail = TailParser::new(io::file_reader(&path).unwrap());
+        assert_eq!(tail.poll(), Ok(~[mk_record([("a", "1"), ("b", "2")])]));
+        assert_eq!(tail.poll(), Ok(~[]));
+        io::file_writer(&path, [io::Append]).unwrap().write_str(":4\na:5");
+        assert_eq!(tail.poll(), Ok(~[mk_record([("a", "3"), ("b", "4")])]));
+        os::remove_file(&path);
+        os::remove_dir(&dir);
+    }
+
+    #[test]
+    fn test_read_ltsv_repaired() {
+        let s = ~"a:1\t\tb:2\r\n\n  \na:3\tb:4\t\t\na:5\n";
+        let (records, repairs) = io::with_str_reader(s, |rd| rd.read_ltsv_repaired()).unwrap();
+        assert_eq!(records, ~[mk_record([("a", "1"), ("b", "2")]),
+                              mk_record([("a", "3"), ("b", "4")]),
+                              mk_record([("a", "5")])]);
+        assert_eq!(repairs, ~[~"line 1: normalized CRLF",
+                              ~"line 1: collapsed doubled tabs",
+                              ~"line 2: dropped empty line",
+                              ~"line 3: dropped empty line",
+                              ~"line 4: collapsed doubled tabs",
+                              ~"line 4: stripped trailing tabs"]);
+    }
+
+    #[test]
+    fn test_hash_chain() {
+        let records = io::with_str_reader(mk_record_string(), |rd| rd.read_ltsv());
+        let s = do io::with_str_writer |wr| {
+            assert_eq!(write_ltsv_hash_chained(wr, records, "_hash"), Ok(()));
+        };
+        assert_eq!(io::with_str_reader(s, |rd| rd.verify_ltsv_hash_chain("_hash")), Ok(()));
+        let tampered = str::replace(s, "普通", "好き");
+        assert_eq!(io::with_str_reader(tampered, |rd| rd.verify_ltsv_hash_chain("_hash")),
+                   Err(~"record 1: hash chain broken"));
+        let s = do io::with_str_writer |wr| {
+            assert_eq!(write_ltsv_hash_chained(wr, records, "bad label"),
+                       Err(~"invalid label: bad label"));
+        };
+        assert_eq!(s, ~"");
+        let s = do io::with_str_writer |wr| {
+            assert_eq!(write_ltsv_hash_chained(wr, records, "tofu"),
+                       Err(~"record 0: already has label tofu"));
+        };
+        assert_eq!(s, ~"");
+    }
+
+    #[test]
+    fn test_record_hash_is_order_independent() {
+        let a = mk_record([("a", "1"), ("b", "2"), ("c", "3")]);
+        let b = mk_record([("c", "3"), ("a", "1"), ("b", "2")]);
+        assert_eq!(record_hash(&a), record_hash(&b));
+        assert_eq!(record_hash(&a).len(), 16);
+        assert!(record_hash(&a) != record_hash(&mk_record([("a", "1"), ("b", "2")])));
+    }
+
+    #[test]
+    fn test_each_ltsv_record_validate_field() {
+        let is_ip = |s: &str| {
+            let parts = str::split_char(s, '.');
+            parts.len() == 4 && parts.all(|p| {
+                let n: Option<u8> = from_str::from_str(*p);
+                n.is_some()
+            })
+        };
+        let s = ~"host:127.0.0.1\nhost:localhost\nua:x\nhost:10.0.0.256\n";
+        let mut results = ~[];
+        do io::with_str_reader(s) |rd| {
+            for rd.each_ltsv_record_validate_field("host", is_ip) |rv| {
+                results.push(rv.map(|r| copy **r));
+            }
+        }
+        assert_eq!(results, ~[Ok(mk_record([("host", "127.0.0.1")])),
+                              Err(~"record 1: invalid host: localhost"),
+                              Err(~"record 2: missing label host"),
+                              Err(~"record 3: invalid host: 10.0.0.256")]);
+    }
+
+    #[test]
+    fn test_write_ltsv_reversed() {
+        let records = ~[mk_record([("a", "1")]), mk_record([("a", "2")]),
+                        mk_record([("a", "3")])];
+        let s = io::with_str_writer(|wr| write_ltsv_reversed(wr, records));
+        assert_eq!(s, ~"a:3\na:2\na:1\n");
+        assert_eq!(io::with_str_reader(s, |rd| rd.read_ltsv()), vec::reversed(records));
+    }
+
+    #[test]
+    fn test_numeric_stats() {
+        let s = ~"t:1.5\nt:3\nt:-0.5\nt:x\nu:1\nt:4\n";
+        let stats = io::with_str_reader(s, |rd| rd.numeric_stats("t")).unwrap();
+        assert_eq!(stats.count, 4);
+        assert_eq!(stats.missing, 1);
+        assert_eq!(stats.invalid, 1);
+        assert_eq!(stats.min, -0.5);
+        assert_eq!(stats.max, 4.0);
+        assert_eq!(stats.sum, 8.0);
+        assert_eq!(stats.mean, 2.0);
+    }
+
+    #[test]
+    fn test_block_value_markers() {
+        let s = ~"id:1\ttrace:<<<\nat foo()\r\n\tat bar()\n>>>\nid:2\ttrace:none\n";
+        let records = do io::with_str_reader(s) |rd| {
+            let opts = ParseOptions {
+                block_value_markers: Some((~"<<<", ~">>>")),
+                ..ParseOptions::new()
+            };
+            LTSVParser::with_options(rd, opts).read_records()
+        };
+        assert_eq!(records, Ok(~[mk_record([("id", "1"), ("trace", "at foo()\n\tat bar()")]),
+                                 mk_record([("id", "2"), ("trace", "none")])]));
+        let records = do io::with_str_reader("trace:<<<\nat foo()\n") |rd| {
+            let opts = ParseOptions {
+                block_value_markers: Some((~"<<<", ~">>>")),
+                ..ParseOptions::new()
+            };
+            LTSVParser::with_options(rd, opts).read_records()
+        };
+        assert_eq!(records, Err(~"EOF while parsing block value"));
+    }
+
+    #[test]
+    fn test_ltsv_gz_round_trip() {
+        let records = io::with_str_reader(mk_record_string(), |rd| rd.read_ltsv());
+        let bytes = do io::with_bytes_writer |wr| {
+            assert_eq!(write_ltsv_gz(wr, records), Ok(()));
+        };
+        assert_eq!(bytes.slice(0, 2).to_vec(), ~[0x1f, 0x8b]);
+        assert_eq!(io::with_bytes_reader(bytes, |rd| read_ltsv_gz(rd)), Ok(copy records));
+        let mut corrupted = copy bytes;
+        let n = corrupted.len();
+        corrupted[n - 5] ^= 0xff;
+        assert!(io::with_bytes_reader(corrupted, |rd| read_ltsv_gz(rd)).is_err());
+        // a final block of the reserved type 3 makes the deflate body invalid.
+        let mut corrupted = copy bytes;
+        corrupted[10] = 0xff;
+        assert_eq!(io::with_bytes_reader(corrupted, |rd| read_ltsv_gz(rd)),
+                   Err(~"corrupt gzip body"));
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(str::to_bytes("123456789")), 0xcbf43926);
+    }
+
+    #[test]
+    fn test_infer_schema() {
+        let s = ~"status:200\tlatency:1\tok:true\tpath:/\n" +
+                "status:404\tlatency:0.5\tok:false\n" +
+                "status:500\tlatency:2\tok:maybe\tpath:/a\n" +
+                "status:x\n";
+        let schema = io::with_str_reader(s, |rd| rd.infer_schema(3)).unwrap();
+        let summary = do schema.map |f| {
+            (copy f.label, f.always_present, f.value_type, f.examples.len())
+        };
+        assert_eq!(summary, ~[(~"latency", true, FloatType, 3),
+                              (~"ok", true, StringType, 3),
+                              (~"path", false, StringType, 2),
+                              (~"status", true, IntType, 3)]);
+    }
+
+    #[test]
+    fn test_read_ltsv_spilling() {
+        let s = ~"a:1\na:2\na:3\na:4\na:5\n";
+        let records = io::with_str_reader(s, |rd| rd.read_ltsv_spilling(2)).unwrap();
+        assert_eq!(records.len(), 5);
+        assert!(records.spilled() > 0);
+        let mut read = ~[];
+        for records.each |record| {
+            read.push(copy *record);
+        }
+        assert_eq!(read, io::with_str_reader(s, |rd| rd.read_ltsv()));
+    }
+
+    #[test]
+    fn test_distinct_values() {
+        let s = ~"status:404\nstatus:200\nhost:a\nstatus:200\nstatus:500\nstatus:404\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.distinct_values("status")),
+                   Ok(~[~"200", ~"404", ~"500"]));
+    }
+
+    #[test]
+    fn test_percentile_estimate() {
+        // 1..1000 in a scrambled order.
+        let s = do io::with_str_writer |wr| {
+            for uint::range(0, 1000) |i| {
+                wr.write_str(fmt!("latency:%u\n", (i * 7919) % 1000 + 1));
+            }
+        };
+        for [(0.5, 500.0), (0.9, 900.0), (0.99, 990.0)].each |&(p, expected)| {
+            let v = io::with_str_reader(s, |rd| rd.percentile_estimate("latency", p)).unwrap();
+            assert!(f64::abs(v - expected) < 20.0);
+        }
+        let v = io::with_str_reader("latency:3\nlatency:1\nlatency:2\n",
+                                    |rd| rd.percentile_estimate("latency", 0.5));
+        assert_eq!(v, Ok(2.0));
+        assert!(io::with_str_reader("a:1\n", |rd| rd.percentile_estimate("latency", 0.5)).is_err());
+    }
+
+    #[test]
+    fn test_write_ltsv_record_with_cmp() {
+        let priority = [~"time", ~"host", ~"status"];
+        let rank = |label: &str| {
+            match vec::position(priority, |p| str::eq_slice(*p, label)) {
+                Some(i) => i,
+                None => priority.len()
+            }
+        };
+        let record = mk_record([("status", "200"), ("ua", "x"), ("host", "a"),
+                                ("time", "t"), ("size", "1")]);
+        let s = do io::with_str_writer |wr| {
+            wr.write_ltsv_record_with_cmp(&record, |a, b| {
+                match rank(a).cmp(&rank(b)) {
+                    core::cmp::Equal => a.cmp(&b),
+                    ord => ord
+                }
+            });
+        };
+        assert_eq!(s, ~"time:t\thost:a\tstatus:200\tsize:1\tua:x");
+    }
+
+    #[test]
+    fn test_find_unexpected_labels() {
+        let s = ~"host:a\tstatus:200\nhost:b\tstatus:404\tdebug:1\tx:2\nhost:c\n";
+        let allowed = [~"host", ~"status"];
+        assert_eq!(io::with_str_reader(s, |rd| rd.find_unexpected_labels(allowed)),
+                   Ok(~[(1, ~"debug"), (1, ~"x")]));
+    }
+
+    #[test]
+    fn test_write_ltsv_truncated() {
+        let records = ~[mk_record([("msg", "hello, world")]), mk_record([("msg", "短い")]),
+                        mk_record([("msg", "海藻")])];
+        let s = io::with_str_writer(|wr| write_ltsv_truncated(wr, records, 5));
+        assert_eq!(s, ~"msg:hello…\nmsg:短…\nmsg:海…\n");
+        let s = io::with_str_writer(|wr| write_ltsv_truncated(wr, records, 6));
+        assert_eq!(s, ~"msg:hello,…\nmsg:短い\nmsg:海藻\n");
+    }
+
+    #[test]
+    fn test_aggregate_by_key() {
+        let s = ~"host:a\tsize:10\nhost:b\tsize:5\nhost:a\tsize:2.5\nhost:a\tsize:0\n";
+        let aggregates = io::with_str_reader(s, |rd| rd.aggregate_by_key("host", "size")).unwrap();
+        assert_eq!(aggregates.len(), 2);
+        assert_eq!(*aggregates.get(&~"a"), (3, 12.5));
+        assert_eq!(*aggregates.get(&~"b"), (1, 5.0));
+        let s = ~"host:a\tsize:10\nsize:5\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.aggregate_by_key("host", "size")),
+                   Err(~"record 1: missing label host"));
+    }
+
+    #[test]
+    fn test_strictness_profiles() {
+        let parse = |s: &str, profile: StrictnessProfile| {
+            io::with_str_reader(s, |rd| LTSVParser::with_profile(rd, profile).read_records())
+        };
+        let s = "user id:1\tname:\n";
+        assert_eq!(parse(s, Lenient), Ok(~[mk_record([("user id", "1"), ("name", "")])]));
+        assert_eq!(parse(s, Standard), Err(~"invalid byte detected"));
+        assert_eq!(parse(s, Strict), Err(~"invalid byte detected"));
+        let s = "id:1\tname:\tid:2\n";
+        assert_eq!(parse(s, Lenient), Ok(~[mk_record([("id", "2"), ("name", "")])]));
+        assert_eq!(parse(s, Standard), Ok(~[mk_record([("id", "2"), ("name", "")])]));
+        assert_eq!(parse(s, Strict), Err(~"value is empty"));
+        let s = "id:1\tid:2\n";
+        assert_eq!(parse(s, Strict), Err(~"duplicate label: id"));
+        let s = "id:1\n\n id:2\n";
+        assert_eq!(parse(s, Standard), Ok(~[mk_record([("id", "1")]), mk_record([("id", "2")])]));
+        assert_eq!(parse(s, Strict), Err(~"invalid byte detected"));
+    }
+
+    #[test]
+    fn test_duplicate_policy() {
+        let s = "id:1\tid:2\n";
+        let records = do io::with_str_reader(s) |rd| {
+            let opts = ParseOptions { duplicates: FirstWins, ..ParseOptions::new() };
+            LTSVParser::with_options(rd, opts).read_records()
+        };
+        assert_eq!(records, Ok(~[mk_record([("id", "1")])]));
+    }
+
+    #[test]
+    fn test_split_field_into_labels() {
+        let mut record = mk_record([("host", "a"), ("cookie", "sid=abc; theme=dark;")]);
+        assert_eq!(split_field_into_labels(&mut record, "cookie", ';', '=', "cookie_"), Ok(()));
+        assert_eq!(record, mk_record([("host", "a"), ("cookie", "sid=abc; theme=dark;"),
+                                      ("cookie_sid", "abc"), ("cookie_theme", "dark")]));
+        let mut record = mk_record([("cookie", "sid=abc; a b=1")]);
+        assert!(split_field_into_labels(&mut record, "cookie", ';', '=', "c_").is_err());
+        assert_eq!(record.len(), 1);
+    }
+
+    #[test]
+    fn test_group_by_two() {
+        let s = ~"host:a\tstatus:200\tn:1\nhost:a\tstatus:404\tn:2\nhost:b\tstatus:200\tn:3\n" +
+                "host:b\tstatus:404\tn:4\nhost:a\tstatus:200\tn:5\n";
+        let groups = io::with_str_reader(s, |rd| rd.group_by_two("host", "status")).unwrap();
+        assert_eq!(groups.len(), 2);
+        let a = groups.get(&~"a");
+        assert_eq!(a.len(), 2);
+        assert_eq!(a.get(&~"200").map(|r| copy *r.get(&~"n")), ~[~"1", ~"5"]);
+        assert_eq!(a.get(&~"404").len(), 1);
+        let b = groups.get(&~"b");
+        assert_eq!(b.len(), 2);
+        assert_eq!(*b.get(&~"404"), ~[mk_record([("host", "b"), ("status", "404"), ("n", "4")])]);
+    }
+
+    #[test]
+    fn test_write_json_array() {
+        let to_json = |s: &str| {
+            let mut n = Ok(0);
+            let json = io::with_str_writer(|wr| {
+                n = io::with_str_reader(s, |rd| write_json_array(rd, wr));
+            });
+            (n, json)
+        };
+        assert_eq!(to_json(""), (Ok(0), ~"[]"));
+        assert_eq!(to_json("a:1\n"), (Ok(1), ~"[{\"a\":\"1\"}]"));
+        assert_eq!(to_json("a:1\nb:x\"y\\z\nc:\x01\n"),
+                   (Ok(3), ~"[{\"a\":\"1\"},{\"b\":\"x\\\"y\\\\z\"},{\"c\":\"\\u0001\"}]"));
+    }
+
+    #[test]
+    fn test_read_ltsv_base64() {
+        // "host:a\tstatus:200\nhost:b\n"
+        let s = "aG9zdDphCXN0YXR1czoyMDAKaG9zdDpiCg==\n";
+        assert_eq!(io::with_str_reader(s, |rd| read_ltsv_base64(rd)),
+                   Ok(~[mk_record([("host", "a"), ("status", "200")]),
+                        mk_record([("host", "b")])]));
+        assert!(io::with_str_reader("aG9z*DphCg==", |rd| read_ltsv_base64(rd)).is_err());
+        assert!(io::with_str_reader("aG9zdDphC", |rd| read_ltsv_base64(rd)).is_err());
+        assert!(io::with_str_reader("aG9zdDph=Cg", |rd| read_ltsv_base64(rd)).is_err());
+    }
+
+    #[test]
+    fn test_validate_bounded() {
+        let s = "host:127.0.0.1\tstatus:200\nhost:localhost\tstatus:404\tsize:10\n";
+        let validate = |limits: ParseLimits| io::with_str_reader(s, |rd| rd.validate_bounded(limits));
+        assert_eq!(validate(ParseLimits::unlimited()), Ok(2));
+        assert_eq!(validate(ParseLimits { max_label_len: 5, ..ParseLimits::unlimited() }),
+                   Err(~"record 0: label exceeds 5 bytes"));
+        assert_eq!(validate(ParseLimits { max_value_len: 8, ..ParseLimits::unlimited() }),
+                   Err(~"record 0: value of host exceeds 8 bytes"));
+        assert_eq!(validate(ParseLimits { max_line_len: 30, ..ParseLimits::unlimited() }),
+                   Err(~"record 1: line exceeds 30 bytes"));
+        assert_eq!(validate(ParseLimits { max_fields: 2, ..ParseLimits::unlimited() }),
+                   Err(~"record 1: more than 2 fields"));
+        assert_eq!(validate(ParseLimits { max_total_bytes: 40, ..ParseLimits::unlimited() }),
+                   Err(~"record 1: input exceeds 40 bytes"));
+        assert!(io::with_str_reader("a:1\tb", |rd| rd.validate_bounded(ParseLimits::unlimited()))
+                .is_err());
+    }
+
+    #[test]
+    fn test_validate_bounded_counts_whitespace() {
+        let padding = str::from_chars(vec::from_elem(1000, ' '));
+        let limits = ParseLimits { max_total_bytes: 100, ..ParseLimits::unlimited() };
+        let s = padding + "\n\na:1\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.validate_bounded(limits)),
+                   Err(~"record 0: input exceeds 100 bytes"));
+        let s = ~"a:1\n" + padding;
+        assert_eq!(io::with_str_reader(s, |rd| rd.validate_bounded(limits)),
+                   Err(~"record 0: input exceeds 100 bytes"));
+        assert_eq!(io::with_str_reader("a:1\n  \n", |rd| rd.validate_bounded(limits)), Ok(1));
+    }
+
+    #[test]
+    fn test_parse_limits_stop_reading_early() {
+        let s = ~"a:" + str::from_chars(vec::from_elem(100000, 'x')) + "\n";
+        do io::with_str_reader(s) |rd| {
+            let limits = ParseLimits { max_value_len: 10, ..ParseLimits::unlimited() };
+            let parser = LTSVParser::with_options(rd, ParseOptions {
+                limits: limits,
+                ..ParseOptions::new()
+            });
+            assert_eq!(parser.read_records(), Err(~"value of a exceeds 10 bytes"));
+            assert!(parser.pos() < 20);
+        }
+    }
+
+    #[test]
+    fn test_write_ltsv_shuffled() {
+        let mut records = ~[];
+        for uint::range(0, 20) |i| {
+            let mut record = LinearMap::new();
+            record.insert(~"n", i.to_str());
+            records.push(record);
+        }
+        let s1 = io::with_str_writer(|wr| write_ltsv_shuffled(wr, records, 42));
+        let s2 = io::with_str_writer(|wr| write_ltsv_shuffled(wr, records, 42));
+        let s3 = io::with_str_writer(|wr| write_ltsv_shuffled(wr, records, 43));
+        assert_eq!(s1, s2);
+        assert!(s1 != s3);
+        let shuffled = io::with_str_reader(s1, |rd| rd.read_ltsv());
+        assert!(shuffled != records);
+        let mut ns = shuffled.map(|r| from_str::from_str::<uint>(*r.get(&~"n")).unwrap());
+        sort::tim_sort(ns);
+        assert_eq!(ns, vec::from_fn(20, |i| i));
+    }
+
+    #[test]
+    fn test_record_sequence_diff() {
+        let a = "n:1\nn:2\nn:3\nn:4\nn:5\nn:6\n";
+        let b = "n:1\nn:3\nn:4\nn:x\nn:6\nn:7\n";
+        let ops = do io::with_str_reader(a) |a| {
+            io::with_str_reader(b, |b| record_sequence_diff(a, b))
+        };
+        assert_eq!(ops, Ok(~[Delete(mk_record([("n", "2")])),
+                             Replace(mk_record([("n", "5")]), mk_record([("n", "x")])),
+                             Insert(mk_record([("n", "7")]))]));
+        let ops = do io::with_str_reader(a) |a| {
+            io::with_str_reader(a, |b| record_sequence_diff(a, b))
+        };
+        assert_eq!(ops, Ok(~[]));
+    }
+
+    #[test]
+    fn test_write_ltsv_with_offsets() {
+        let records = io::with_str_reader(mk_record_string(), |rd| rd.read_ltsv());
+        let mut offsets = Ok(~[]);
+        let bytes = io::with_bytes_writer(|wr| {
+            offsets = write_ltsv_with_offsets(wr, records, "_offset");
+        });
+        let offsets = offsets.unwrap();
+        assert_eq!(offsets.len(), 2);
+        assert_eq!(offsets[0], 0);
+        for offsets.eachi |i, &offset| {
+            let rest = bytes.slice(offset, bytes.len());
+            let record = do io::with_bytes_reader(rest) |rd| {
+                match LTSVParser::new(rd).parse_record() {
+                    ParseOk(_, _, record) => record,
+                    ParseError(reason) => fail!(reason)
+                }
+            };
+            assert_eq!(*record.get(&~"_offset"), offset.to_str());
+            assert_eq!(record.get(&~"tofu"), records[i].get(&~"tofu"));
+        }
+    }
+
+    #[test]
+    fn test_build_bloom() {
+        let s = do io::with_str_writer |wr| {
+            for uint::range(0, 200) |i| {
+                wr.write_str(fmt!("host:10.0.0.%u\tstatus:200\n", i));
+            }
+        };
+        let bloom = io::with_str_reader(s, |rd| rd.build_bloom("host", 4096, 4)).unwrap();
+        for uint::range(0, 200) |i| {
+            assert!(bloom.contains(fmt!("10.0.0.%u", i)));
+        }
+        let mut false_positives = 0u;
+        for uint::range(0, 200) |i| {
+            if bloom.contains(fmt!("10.0.1.%u", i)) { false_positives += 1; }
+        }
+        assert!(false_positives < 20);
+        assert!(io::with_str_reader(s, |rd| rd.build_bloom("host", 0, 4)).is_err());
+        assert!(BloomFilter::new(0, 4).is_err());
+        assert!(BloomFilter::new(4096, 0).is_err());
+        let mut bloom = BloomFilter::new(64, 2).unwrap();
+        bloom.insert("a");
+        assert!(bloom.contains("a"));
+    }
+
+    #[test]
+    fn test_each_ltsv_record_constrained() {
+        let error_on_500: &fn(&Record) -> Option<~str> = |r| {
+            if r.find(&~"status") == Some(&~"500") && !r.contains_key(&~"error") {
+                Some(~"status 500 requires error")
+            } else {
+                None
+            }
+        };
+        let has_host: &fn(&Record) -> Option<~str> = |r| {
+            if r.contains_key(&~"host") { None } else { Some(~"missing host") }
+        };
+        let s = ~"host:a\tstatus:200\nhost:b\tstatus:500\nhost:c\tstatus:500\terror:x\nstatus:500\n";
+        let mut results = ~[];
+        do io::with_str_reader(s) |rd| {
+            for rd.each_ltsv_record_constrained([error_on_500, has_host]) |rv| {
+                results.push(rv.map(|r| copy *r.get(&~"status")));
+            }
+        }
+        assert_eq!(results, ~[Ok(~"200"),
+                              Err(~"record 1: status 500 requires error"),
+                              Ok(~"500"),
+                              Err(~"record 3: status 500 requires error")]);
+    }
+
+    #[test]
+    fn test_write_tsv_compact() {
+        let records = ~[mk_record([("req_method", "GET"), ("req_path", "/"), ("host", "a")]),
+                        mk_record([("req_method", "POST"), ("req_size", "10")])];
+        let columns = [~"req_method", ~"req_path", ~"req_size"];
+        let s = do io::with_str_writer |wr| {
+            assert_eq!(write_tsv_compact(wr, records, "req_", columns), Ok(()));
+        };
+        assert_eq!(s, ~"# prefix: req_\nmethod\tpath\tsize\nGET\t/\t\nPOST\t\t10\n");
+        let s = io::with_str_writer(|wr| {
+            assert!(write_tsv_compact(wr, records, "req_", [~"host"]).is_err());
+        });
+        assert_eq!(s, ~"");
+    }
+
+    #[test]
+    fn test_audit_fields() {
+        let s = ~"host:a\tua:x\x07y\nhost:\tmsg:豆\x01\nhost:b\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.audit_fields()),
+                   Ok(~[(0, ~"ua", ControlChar),
+                        (1, ~"host", EmptyValue),
+                        (1, ~"msg", NonAscii),
+                        (1, ~"msg", ControlChar)]));
+    }
+
+    #[test]
+    fn test_merge_sorted_ltsv() {
+        let a = @io::BytesReader { bytes: str::as_bytes_slice("ts:1\tsrc:a\nts:3\tsrc:a\nts:5\tsrc:a\n"),
+                                   pos: 0 } as @io::Reader;
+        let b = @io::BytesReader { bytes: str::as_bytes_slice("ts:2\tsrc:b\nts:3\tsrc:b\nts:6\tsrc:b\n"),
+                                   pos: 0 } as @io::Reader;
+        let mut n = Ok(0);
+        let s = io::with_str_writer(|wr| n = merge_sorted_ltsv(~[a, b], wr, "ts"));
+        assert_eq!(n, Ok(6));
+        let records = io::with_str_reader(s, |rd| rd.read_ltsv());
+        let order = records.map(|r| fmt!("%s%s", *r.get(&~"ts"), *r.get(&~"src")));
+        assert_eq!(order, ~[~"1a", ~"2b", ~"3a", ~"3b", ~"5a", ~"6b"]);
+        let a = @io::BytesReader { bytes: str::as_bytes_slice("ts:1\nts:4\n"),
+                                   pos: 0 } as @io::Reader;
+        let b = @io::BytesReader { bytes: str::as_bytes_slice("ts:2\nts:3\nsrc:b\n"),
+                                   pos: 0 } as @io::Reader;
+        let mut n = Ok(0);
+        io::with_str_writer(|wr| n = merge_sorted_ltsv(~[a, b], wr, "ts"));
+        assert_eq!(n, Err(~"reader 1: record 2: missing label ts"));
+        let a = @io::BytesReader { bytes: str::as_bytes_slice("ts:9\nts:10\n"),
+                                   pos: 0 } as @io::Reader;
+        let b = @io::BytesReader { bytes: str::as_bytes_slice("ts:8\nts:11\n"),
+                                   pos: 0 } as @io::Reader;
+        let s = io::with_str_writer(|wr| n = merge_sorted_ltsv(~[a, b], wr, "ts"));
+        assert_eq!(n, Ok(4));
+        let records = io::with_str_reader(s, |rd| rd.read_ltsv());
+        assert_eq!(records.map(|r| copy *r.get(&~"ts")), ~[~"8", ~"9", ~"10", ~"11"]);
+    }
+
+    #[test]
+    fn test_reservoir_sample() {
+        let s = str::concat(vec::from_fn(100, |i| fmt!("n:%u\n", i)));
+        let a = io::with_str_reader(s, |rd| rd.reservoir_sample(10, 7)).unwrap();
+        let b = io::with_str_reader(s, |rd| rd.reservoir_sample(10, 7)).unwrap();
+        let c = io::with_str_reader(s, |rd| rd.reservoir_sample(10, 8)).unwrap();
+        assert_eq!(a.len(), 10);
+        assert_eq!(a, b);
+        assert!(a != c);
+        let small = io::with_str_reader("n:1\nn:2\n", |rd| rd.reservoir_sample(10, 7)).unwrap();
+        assert_eq!(small.len(), 2);
+    }
+
+    #[test]
+    fn test_write_ltsv_with_footer() {
+        let records = ~[mk_record([("n", "1")]), mk_record([("n", "2")])];
+        let footer = mk_record([("_records", "2"), ("_generated", "2013-04-01")]);
+        let mut rv = Ok(());
+        let s = io::with_str_writer(|wr| rv = write_ltsv_with_footer(wr, records, &footer));
+        assert_eq!(rv, Ok(()));
+        let read = io::with_str_reader(s, |rd| rd.read_ltsv());
+        assert_eq!(read.len(), 3);
+        assert_eq!(read.slice(0, 2).to_vec(), records);
+        assert!(!is_footer_record(&read[0]) && !is_footer_record(&read[1]));
+        assert!(is_footer_record(&read[2]));
+        let mut marked = copy footer;
+        marked.insert(~"_footer", ~"1");
+        assert_eq!(read[2], marked);
+        let bad = ~[mk_record([("_footer", "1")])];
+        let mut rv = Ok(());
+        io::with_str_writer(|wr| rv = write_ltsv_with_footer(wr, bad, &footer));
+        assert_eq!(rv, Err(~"record 0: already has label _footer"));
+    }
+
+    fn mk_spec() -> LtsvSpec {
+        LtsvSpec {
+            labels: ~[
+                LabelSpec { label: ~"host", required: true, value_type: StringType, allowed: ~[] },
+                LabelSpec { label: ~"status", required: true, value_type: IntType, allowed: ~[] },
+                LabelSpec { label: ~"method", required: false, value_type: StringType,
+                            allowed: ~[~"GET", ~"POST"] }
+            ],
+            allow_unknown: false
+        }
+    }
+
+    #[test]
+    fn test_validate_against_spec() {
+        let s = "host:a\tstatus:200\tmethod:GET\nhost:b\tstatus:404\n";
+        let rv = io::with_str_reader(s, |rd| rd.validate_against_spec(&mk_spec()));
+        assert_eq!(rv, Ok(()));
+    }
+
+    #[test]
+    fn test_validate_against_spec_violations() {
+        let s = "host:a\tstatus:ok\nstatus:200\tmethod:PUT\tua:curl\n";
+        let rv = io::with_str_reader(s, |rd| rd.validate_against_spec(&mk_spec()));
+        assert_eq!(rv, Err(~[
+            SpecViolation { index: 0, reason: ~"label status: ok is not int" },
+            SpecViolation { index: 1, reason: ~"missing label host" },
+            SpecViolation { index: 1, reason: ~"label method: PUT is not an allowed value" },
+            SpecViolation { index: 1, reason: ~"unknown label ua" }
+        ]));
+    }
+
+    #[test]
+    fn test_bench_parse() {
+        let s = str::concat(vec::from_fn(50, |i| fmt!("n:%u\thost:example.com\n", i)));
+        let rv = io::with_str_reader(s, |rd| rd.bench_parse(2, 5));
+        assert_eq!(rv.records, 50);
+        assert!(rv.min_ns > 0.0);
+        assert!(rv.min_ns <= rv.max_ns);
+        assert!(rv.mean_ns > 0.0);
+    }
+
+    #[test]
+    fn test_record_to_combined_log() {
+        let record = mk_record([("host", "127.0.0.1"), ("user", "frank"),
+                                ("time", "[10/Oct/2000:13:55:36 -0700]"),
+                                ("req", "GET /apache_pb.gif HTTP/1.0"), ("status", "200"),
+                                ("size", "2326"), ("referer", "http://www.example.com/start.html"),
+                                ("ua", "Mozilla/4.08")]);
+        assert_eq!(record_to_combined_log(&record),
+                   Ok(~"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] " +
+                      "\"GET /apache_pb.gif HTTP/1.0\" 200 2326 " +
+                      "\"http://www.example.com/start.html\" \"Mozilla/4.08\""));
+        let mut record = record;
+        record.remove(&~"status");
+        assert_eq!(record_to_combined_log(&record), Err(~"missing label status"));
+    }
+
+    #[test]
+    fn test_record_from_combined_log() {
+        let line = ~"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] " +
+            "\"GET /apache_pb.gif HTTP/1.0\" 200 2326 " +
+            "\"http://www.example.com/start.html\" \"Mozilla/4.08\"\n";
+        let record = record_from_combined_log(line).unwrap();
+        assert_eq!(record, mk_record([("host", "127.0.0.1"), ("user", "frank"),
+                                      ("time", "[10/Oct/2000:13:55:36 -0700]"),
+                                      ("req", "GET /apache_pb.gif HTTP/1.0"), ("status", "200"),
+                                      ("size", "2326"),
+                                      ("referer", "http://www.example.com/start.html"),
+                                      ("ua", "Mozilla/4.08")]));
+        assert_eq!(record_to_combined_log(&record), Ok(str::trim_right(line).to_owned()));
+        let common = "10.0.0.1 - - [10/Oct/2000:13:55:36 -0700] \"GET / HTTP/1.0\" 404 -";
+        assert_eq!(record_from_combined_log(common).unwrap().find(&~"size"), Some(&~"-"));
+    }
+
+    #[test]
+    fn test_record_from_combined_log_malformed() {
+        assert!(record_from_combined_log("127.0.0.1 - - [10/Oct/2000:13:55:36 -0700").is_err());
+        assert!(record_from_combined_log("127.0.0.1 - - [t] \"GET /\" ok 10").is_err());
+        assert!(record_from_combined_log("127.0.0.1 - - [t] \"GET /\" 200").is_err());
+    }
+
+    #[test]
+    fn test_check_unique() {
+        let s = "id:1\nid:2\nname:x\nid:1\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.check_unique("id", true)),
+                   Err(~"record 3: duplicate id 1 (first seen in record 0)"));
+        assert_eq!(io::with_str_reader(s, |rd| rd.check_unique("id", false)),
+                   Err(~"record 2: missing label id"));
+        let s = "id:1\nid:2\nname:x\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.check_unique("id", true)), Ok(()));
+    }
+
+    #[test]
+    fn test_rolling_aggregate() {
+        let s = "ts:0\tv:1\nts:5\tv:3\nts:10\tv:8\nts:30\tv:2\n";
+        let mut means = ~[];
+        let rv = do io::with_str_reader(s) |rd| {
+            rd.rolling_aggregate("ts", "v", 10, |end, mean| { means.push((end, mean)); true })
+        };
+        assert_eq!(rv, Ok(()));
+        assert_eq!(means, ~[(0, 1.0), (5, 2.0), (10, 5.5), (30, 2.0)]);
+        let s = "ts:5\tv:1\nts:4\tv:1\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.rolling_aggregate("ts", "v", 10, |_, _| true)),
+                   Err(~"record 1: ts is out of order"));
+    }
+
+    #[test]
+    fn test_write_ltsv_renamed() {
+        let records = ~[mk_record([("remote_addr", "10.0.0.1"), ("status", "200")])];
+        let renames = ~[(~"remote_addr", ~"host")];
+        let mut rv = Ok(());
+        let s = io::with_str_writer(|wr| rv = write_ltsv_renamed(wr, records, renames));
+        assert_eq!(rv, Ok(()));
+        assert_eq!(io::with_str_reader(s, |rd| rd.read_ltsv()),
+                   ~[mk_record([("host", "10.0.0.1"), ("status", "200")])]);
+        assert_eq!(records[0].find(&~"remote_addr"), Some(&~"10.0.0.1"));
+        let renames = ~[(~"remote_addr", ~"status")];
+        let mut rv = Ok(());
+        io::with_str_writer(|wr| rv = write_ltsv_renamed(wr, records, renames));
+        assert_eq!(rv, Err(~"record 0: duplicate label status after renaming"));
+        let renames = ~[(~"status", ~"bad label")];
+        let mut rv = Ok(());
+        io::with_str_writer(|wr| rv = write_ltsv_renamed(wr, records, renames));
+        assert_eq!(rv, Err(~"invalid label: bad label"));
+    }
+
+    #[test]
+    fn test_length_prefixed_values() {
+        let s = ~"msg:7:a\tb\ncde\tn:1:5\nmsg:0:\n";
+        let records = do io::with_str_reader(s) |rd| {
+            let opts = ParseOptions { length_prefixed_values: true, ..ParseOptions::new() };
+            LTSVParser::with_options(rd, opts).read_records()
+        };
+        assert_eq!(records, Ok(~[mk_record([("msg", "a\tb\ncde"), ("n", "5")]),
+                                 mk_record([("msg", "")])]));
+        let records = do io::with_str_reader("msg:9:short\n") |rd| {
+            let opts = ParseOptions { length_prefixed_values: true, ..ParseOptions::new() };
+            LTSVParser::with_options(rd, opts).read_records()
+        };
+        assert!(records.is_err());
+    }
+
+    #[test]
+    fn test_top_k_values() {
+        let s = "host:a\nhost:b\nhost:c\nhost:b\nhost:c\nhost:a\nhost:c\nstatus:200\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.top_k_values("host", 2)),
+                   Ok(~[(~"c", 3), (~"a", 2)]));
+        assert_eq!(io::with_str_reader(s, |rd| rd.top_k_values("host", 10)).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_write_ltsv_with_manifest() {
+        let records = ~[mk_record([("n", "1")]), mk_record([("n", "2"), ("host", "a")])];
+        let mut rv = Ok(());
+        let mut data = ~"";
+        let manifest = do io::with_str_writer |mwr| {
+            data = io::with_str_writer(|wr| rv = write_ltsv_with_manifest(wr, mwr, records));
+        };
+        assert_eq!(rv, Ok(()));
+        let read = io::with_str_reader(data, |rd| rd.read_ltsv());
+        let hashes = do str::lines(manifest).map |line| { line.to_owned() };
+        assert_eq!(hashes, read.map(|r| record_hash(r)));
+    }
+
+    #[test]
+    fn test_ltsv_binary_round_trip() {
+        let records = ~[mk_record([("msg", "a\tb\nc"), ("host", "127.0.0.1")]),
+                        mk_record([("empty", "")]),
+                        LinearMap::new()];
+        let mut rv = Ok(());
+        let bytes = io::with_bytes_writer(|wr| rv = write_ltsv_binary(wr, records));
+        assert_eq!(rv, Ok(()));
+        assert_eq!(bytes.slice(0, 5).to_vec(), ~[0x4c, 0x54, 0x53, 0x42, 1]);
+        assert_eq!(io::with_bytes_reader(bytes, |rd| read_ltsv_binary(rd)), Ok(records));
+        let truncated = bytes.slice(0, bytes.len() - 2).to_vec();
+        assert!(io::with_bytes_reader(truncated, |rd| read_ltsv_binary(rd)).is_err());
+        assert!(io::with_bytes_reader([0x4c, 0x54], |rd| read_ltsv_binary(rd)).is_err());
+        // one field whose label claims to be 0xffffffff bytes long.
+        let huge = ~[0x4c, 0x54, 0x53, 0x42, 1, 1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0x61];
+        assert_eq!(io::with_bytes_reader(huge, |rd| read_ltsv_binary(rd)),
+                   Err(~"truncated LTSV binary stream"));
+    }
+
+    #[test]
+    fn test_check_mutually_exclusive() {
+        let groups = ~[~[~"ipv4", ~"ipv6"]];
+        let s = "ipv4:10.0.0.1\nipv6:::1\thost:a\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.check_mutually_exclusive(groups)), Ok(()));
+        let s = "ipv4:10.0.0.1\nipv4:10.0.0.2\tipv6:::1\nhost:a\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.check_mutually_exclusive(groups)),
+                   Err(~"record 1: expected exactly one of ipv4, ipv6, found 2"));
+        let s = "host:a\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.check_mutually_exclusive(groups)),
+                   Err(~"record 0: expected exactly one of ipv4, ipv6, found 0"));
+    }
+
+    #[test]
+    fn test_periodic_checksum() {
+        let records = vec::from_fn(7, |i| {
+            let mut record = LinearMap::new();
+            record.insert(~"n", i.to_str());
+            record
+        });
+        let mut rv = Ok(());
+        let s = io::with_str_writer(|wr| {
+            rv = write_ltsv_with_periodic_checksum(wr, records, 3, "_sum")
+        });
+        assert_eq!(rv, Ok(()));
+        assert_eq!(str::lines(s).len(), 9);
+        let read = io::with_str_reader(s, |rd| rd.read_ltsv());
+        assert_eq!(read.filtered(|r| !r.contains_key(&~"_sum")), records);
+        assert_eq!(io::with_str_reader(s, |rd| rd.verify_periodic_checksum("_sum")), Ok(6));
+        let tampered = str::replace(s, "n:4\n", "n:40\n");
+        assert_eq!(io::with_str_reader(tampered, |rd| rd.verify_periodic_checksum("_sum")),
+                   Err(~"records 3-5: checksum mismatch"));
+        let s = "_sum:0123456789abcdef\nn:0\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.verify_periodic_checksum("_sum")),
+                   Err(~"record 0: checksum mismatch"));
+        let mut rv = Ok(());
+        let s = io::with_str_writer(|wr| {
+            rv = write_ltsv_with_periodic_checksum(wr, records, 3, "bad label")
+        });
+        assert_eq!(rv, Err(~"invalid label: bad label"));
+        assert_eq!(s, ~"");
+    }
+
+    #[test]
+    fn test_pivot() {
+        let s = ~"host:a\tmetric:cpu\tv:10\nhost:a\tmetric:mem\tv:20\n" +
+            "host:b\tmetric:cpu\tv:30\nhost:a\tmetric:cpu\tv:15\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.pivot("host", "metric", "v")),
+                   Ok(~[mk_record([("host", "a"), ("cpu", "15"), ("mem", "20")]),
+                        mk_record([("host", "b"), ("cpu", "30")])]));
+        let s = "host:a\tmetric:cpu\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.pivot("host", "metric", "v")),
+                   Err(~"record 0: missing label v"));
+    }
+
+    #[test]
+    fn test_unpivot() {
+        let record = mk_record([("host", "a"), ("cpu", "15"), ("mem", "20"), ("note", "x")]);
+        let long = unpivot(&record, [~"host"], [~"cpu", ~"mem", ~"disk"]);
+        assert_eq!(long, ~[mk_record([("host", "a"), ("variable", "cpu"), ("value", "15")]),
+                           mk_record([("host", "a"), ("variable", "mem"), ("value", "20")])]);
+    }
+
+    #[test]
+    fn test_each_ltsv_record_with_lazy_defaults() {
+        let vhost: @fn(&Record) -> ~str = |r| fmt!("%s.example.com", *r.get(&~"host"));
+        let defaults = ~[(~"vhost", vhost)];
+        let mut records = ~[];
+        do io::with_str_reader("host:www\nhost:api\tvhost:api.test\n") |rd| {
+            for rd.each_ltsv_record_with_lazy_defaults(defaults) |record| {
+                records.push(copy *record);
+            }
+        }
+        assert_eq!(records, ~[mk_record([("host", "www"), ("vhost", "www.example.com")]),
+                              mk_record([("host", "api"), ("vhost", "api.test")])]);
+    }
+
+    #[test]
+    fn test_histogram_counts() {
+        assert_eq!(histogram_counts([1.0, 2.0, 2.5, 9.0, 10.0, 5.5], 3),
+                   (1.0, 10.0, ~[3, 1, 2]));
+        assert_eq!(histogram_counts([4.0, 4.0], 2), (4.0, 4.0, ~[2, 0]));
+    }
+
+    #[test]
+    fn test_histogram_text() {
+        let s = "v:1\nv:2\nv:2.5\nv:9\nv:10\nv:5.5\nv:x\nhost:a\n";
+        let text = io::with_str_reader(s, |rd| rd.histogram_text("v", 3, 6)).unwrap();
+        let lines = str::lines(text);
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("| ###### 3"));
+        assert!(lines[1].ends_with("| ## 1"));
+        assert!(lines[2].ends_with("| #### 2"));
+        assert!(io::with_str_reader(s, |rd| rd.histogram_text("v", 0, 6)).is_err());
+    }
+
+    #[test]
+    fn test_check_label_cardinality() {
+        let s = "host:a\tstatus:200\nhost:b\tsize:10\nhost:c\tstatus:404\tua:x\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.check_label_cardinality(4)), Ok(4));
+        assert_eq!(io::with_str_reader(s, |rd| rd.check_label_cardinality(3)),
+                   Err(~"record 2: 4 distinct labels exceed the limit of 3"));
+    }
+
+    #[test]
+    fn test_write_ltsv_encoded() {
+        let records = ~[mk_record([("path", "/a b?q=1&r=豆"), ("host", "a b")])];
+        let mut rv = Ok(());
+        let s = io::with_str_writer(|wr| rv = write_ltsv_encoded(wr, records, [~"path"]));
+        assert_eq!(rv, Ok(()));
+        let raw = io::with_str_reader(s, |rd| rd.read_ltsv());
+        assert_eq!(raw[0].get(&~"path"), &~"%2Fa%20b%3Fq%3D1%26r%3D%E8%B1%86");
+        assert_eq!(raw[0].get(&~"host"), &~"a b");
+        let decoded = do io::with_str_reader(s) |rd| {
+            let opts = ParseOptions { percent_decode_labels: ~[~"path"], ..ParseOptions::new() };
+            LTSVParser::with_options(rd, opts).read_records()
+        };
+        assert_eq!(decoded, Ok(records));
+        let bad = do io::with_str_reader("path:%G0\n") |rd| {
+            let opts = ParseOptions { percent_decode_labels: ~[~"path"], ..ParseOptions::new() };
+            LTSVParser::with_options(rd, opts).read_records()
+        };
+        assert!(bad.is_err());
+    }
+
+    #[test]
+    fn test_each_ltsv_run_by() {
+        let s = "u:a\tn:1\nu:a\tn:2\nu:b\tn:3\nu:a\tn:4\nu:c\tn:5\nu:c\tn:6\n";
+        let mut runs = ~[];
+        let rv = do io::with_str_reader(s) |rd| {
+            do rd.each_ltsv_run_by("u") |run| {
+                runs.push(run.map(|r| copy *r.get(&~"n")));
+                true
+            }
+        };
+        assert_eq!(rv, Ok(()));
+        assert_eq!(runs, ~[~[~"1", ~"2"], ~[~"3"], ~[~"4"], ~[~"5", ~"6"]]);
+        let mut nruns = 0;
+        let rv = do io::with_str_reader(s) |rd| {
+            rd.each_ltsv_run_by("u", |_| { nruns += 1; nruns < 2 })
+        };
+        assert_eq!((rv, nruns), (Ok(()), 2));
+        assert_eq!(io::with_str_reader("u:a\nn:1\n", |rd| rd.each_ltsv_run_by("u", |_| true)),
+                   Err(~"record 1: missing label u"));
+    }
+
+    #[test]
+    fn test_check_numeric_range() {
+        let s = "cpu:0\ncpu:55.5\ncpu:100\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.check_numeric_range("cpu", 0.0, 100.0)), Ok(()));
+    }
+
+    #[test]
+    fn test_check_numeric_range_violations() {
+        let s = "cpu:0\ncpu:100.5\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.check_numeric_range("cpu", 0.0, 100.0)),
+                   Err(~"record 1: cpu is out of range: 100.5"));
+        let s = "cpu:0\ncpu:high\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.check_numeric_range("cpu", 0.0, 100.0)),
+                   Err(~"record 1: cpu is not a number: high"));
+        let s = "cpu:0\nmem:1\n";
+        assert_eq!(io::with_str_reader(s, |rd| rd.check_numeric_range("cpu", 0.0, 100.0)),
+                   Err(~"record 1: missing label cpu"));
+    }
+
+    #[test]
+    fn test_throttle_ltsv() {
+        let s = ~"n:1\nn:2\nn:3\nn:4\nn:5\n";
+        let start = time::precise_time_ns();
+        let mut rv = Ok(0);
+        let out = do io::with_str_writer |wr| {
+            rv = io::with_str_reader(s, |rd| throttle_ltsv(rd, wr, 20));
+        };
+        let elapsed_ms = (time::precise_time_ns() - start) / 1000000;
+        assert_eq!(rv, Ok(5));
+        assert_eq!(io::with_str_reader(out, |rd| rd.read_ltsv()),
+                   io::with_str_reader(s, |rd| rd.read_ltsv()));
+        // the fifth record is due 200ms after the first.
+        assert!(elapsed_ms >= 190);
+        do io::with_str_writer |wr| {
+            assert!(io::with_str_reader(s, |rd| throttle_ltsv(rd, wr, 0)).is_err());
+        };
+    }
+
+    #[test]
+    fn test_completeness_report() {
+        let s = "host:a\tua:x\nhost:b\tua:\nhost:c\nhost:d\treferer:\n";
+        let report = io::with_str_reader(s, |rd| rd.completeness_report()).unwrap();
+        assert_eq!(report.len(), 3);
+        assert_eq!(report.find(&~"host"), Some(&1.0));
+        assert_eq!(report.find(&~"ua"), Some(&0.25));
+        assert_eq!(report.find(&~"referer"), Some(&0.0));
+    }
+
+    #[test]
+    fn test_ltsv_typed_round_trip() {
+        let schema = ~[(~"status", IntType), (~"latency", FloatType)];
+        let records = ~[mk_record([("status", "200"), ("latency", "0.25"), ("host", "a")]),
+                        mk_record([("status", "404"), ("latency", "3")])];
+        let mut rv = Ok(());
+        let s = io::with_str_writer(|wr| rv = write_ltsv_typed(wr, schema, records));
+        assert_eq!(rv, Ok(()));
+        assert!(s.starts_with("status:int\tlatency:float\n"));
+        assert_eq!(io::with_str_reader(s, |rd| read_ltsv_typed(rd)),
+                   Ok((~[(~"latency", FloatType), (~"status", IntType)], records)));
+        let bad = ~[mk_record([("status", "ok")])];
+        let mut rv = Ok(());
+        io::with_str_writer(|wr| rv = write_ltsv_typed(wr, schema, bad));
+        assert_eq!(rv, Err(~"record 0: status is not int: ok"));
+        let mut rv = Ok(());
+        let s = io::with_str_writer(|wr| rv = write_ltsv_typed(wr, [], records));
+        assert_eq!(rv, Err(~"schema is empty"));
+        assert_eq!(s, ~"");
+        let s = "status:int\nstatus:200\nstatus:ok\n";
+        assert_eq!(io::with_str_reader(s, |rd| read_ltsv_typed(rd)),
+                   Err(~"record 1: status is not int: ok"));
+    }
+
+    #[test]
+    fn test_redact_matching() {
+        let s = ~"card:4111111111111111\tuser:bob\nnote:call 5551234\tid:42\n";
+        let looks_like_card = |v: &str| v.len() >= 12 && str::all(v, char::is_digit);
+        let mut rv = Ok(());
+        let out = do io::with_str_writer |wr| {
+            rv = do io::with_str_reader(s) |rd| {
+                redact_matching(rd, wr, looks_like_card, "[REDACTED]")
+            };
+        };
+        assert_eq!(rv, Ok(()));
+        assert_eq!(io::with_str_reader(out, |rd| rd.read_ltsv()),
+                   ~[mk_record([("card", "[REDACTED]"), ("user", "bob")]),
+                     mk_record([("note", "call 5551234"), ("id", "42")])]);
+    }
+
+    #[test]
+    fn test_record_to_canonical_json() {
+        let a = mk_record([("b", "2"), ("a", "say \"hi\"\t"), ("c", "豆")]);
+        let b = mk_record([("c", "豆"), ("a", "say \"hi\"\t"), ("b", "2")]);
+        let expected = ~"{\"a\":\"say \\\"hi\\\"\\t\",\"b\":\"2\",\"c\":\"豆\"}";
+        assert_eq!(record_to_canonical_json(&a), expected);
+        assert_eq!(record_to_canonical_json(&b), expected);
+        assert_eq!(record_to_canonical_json(&LinearMap::new()), ~"{}");
+    }
+
+    #[test]
+    fn test_max_field_widths() {
+        let s = "host:a\tua:curl/7.29\nhost:example.com\tua:\nhost:豆\n";
+        let widths = io::with_str_reader(s, |rd| rd.max_field_widths()).unwrap();
+        assert_eq!(widths.len(), 2);
+        assert_eq!(widths.find(&~"host"), Some(&11));
+        assert_eq!(widths.find(&~"ua"), Some(&9));
+    }
+
+    #[test]
+    fn test_tolerate_eof_in_label() {
+        let s = ~"a:1\nb:2\t ";
+        let records = do io::with_str_reader(s) |rd| {
+            let opts = ParseOptions { tolerate_eof_in_label: true, ..ParseOptions::new() };
+            LTSVParser::with_options(rd, opts).read_records()
+        };
+        assert_eq!(records, Ok(~[mk_record([("a", "1")]), mk_record([("b", "2")])]));
+        let records = io::with_str_reader(s, |rd| LTSVParser::new(rd).read_records());
+        assert_eq!(records, Err(~"EOF while parsing field label"));
+    }
+
+    #[test]
+    fn test_tolerate_eof_in_partial_label() {
+        let records = do io::with_str_reader("a:1\nb:2\tc") |rd| {
+            let opts = ParseOptions { tolerate_eof_in_label: true, ..ParseOptions::new() };
+            LTSVParser::with_options(rd, opts).read_records()
+        };
+        assert_eq!(records, Err(~"EOF while parsing field label"));
+    }
+
+    #[test]
+    fn test_split_ltsv() {
+        let s = str::concat(vec::from_fn(200, |i| fmt!("n:%u\n", i)));
+        let split = |seed| {
+            let mut rv = Ok((0, 0));
+            let mut train = ~"";
+            let test = do io::with_str_writer |twr| {
+                train = io::with_str_writer(|wr| {
+                    rv = io::with_str_reader(s, |rd| split_ltsv(rd, wr, twr, 0.8, seed))
+                });
+            };
+            (rv, train, test)
+        };
+        let (rv1, train1, test1) = split(42);
+        let (rv2, train2, test2) = split(42);
+        let (ntrain, ntest) = rv1.unwrap();
+        assert_eq!(ntrain + ntest, 200);
+        assert!(ntrain > 140 && ntrain < 180);
+        assert_eq!(rv2, Ok((ntrain, ntest)));
+        assert_eq!(str::lines(test1).len(), ntest);
+        assert_eq!((train1, test1), (train2, test2));
+    }
+
+    #[test]
+    fn test_cooccurrence() {
+        let s = ~"method:GET\tstatus:200\nmethod:GET\tstatus:200\nmethod:POST\tstatus:500\n" +
+            "method:GET\tstatus:404\nmethod:GET\nstatus:200\n";
+        let counts = io::with_str_reader(s, |rd| rd.cooccurrence("method", "status")).unwrap();
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts.find(&(~"GET", ~"200")), Some(&2));
+        assert_eq!(counts.find(&(~"POST", ~"500")), Some(&1));
+        assert_eq!(counts.find(&(~"GET", ~"404")), Some(&1));
+    }
+
+    #[test]
+    fn test_find_corrupted_records() {
+        let records = vec::from_fn(4, |i| {
+            let mut record = LinearMap::new();
+            record.insert(~"n", i.to_str());
+            let hash = record_hash(&record);
+            record.insert(~"_hash", hash);
+            record
+        });
+        let s = io::with_str_writer(|wr| wr.write_ltsv(records));
+        assert_eq!(io::with_str_reader(s, |rd| rd.find_corrupted_records("_hash")), Ok(~[]));
+        let tampered = str::replace(s, "n:2\t", "n:7\t");
+        let tampered = str::replace(tampered, "\tn:2\n", "\tn:7\n");
+        let mut expected = copy records[2];
+        expected.insert(~"n", ~"7");
+        assert_eq!(io::with_str_reader(tampered, |rd| rd.find_corrupted_records("_hash")),
+                   Ok(~[(2, expected)]));
+    }
+
+    #[test]
+    fn test_field_hooks() {
+        let upper: @fn(&str) -> Result<~str, ~str> = |v| Ok(str::to_upper(v));
+        let status: @fn(&str) -> Result<~str, ~str> = |v| {
+            match from_str::from_str::<uint>(v) {
+                Some(_) => Ok(v.to_owned()),
+                None => Err(fmt!("not a status code: %s", v))
+            }
+        };
+        let parse = |s: &str| {
+            do io::with_str_reader(s) |rd| {
+                let opts = ParseOptions {
+                    field_hooks: ~[(~"method", upper), (~"status", status)],
+                    ..ParseOptions::new()
+                };
+                LTSVParser::with_options(rd, opts).read_records()
+            }
+        };
+        assert_eq!(parse("method:get\tstatus:200\tpath:/a\n"),
+                   Ok(~[mk_record([("method", "GET"), ("status", "200"), ("path", "/a")])]));
+        assert_eq!(parse("method:get\tstatus:ok\n"),
+                   Err(~"label status: not a status code: ok"));
+    }
+
+    #[test]
+    fn test_ltsv_delta_round_trip() {
+        let records = ~[mk_record([("host", "a"), ("ua", "curl"), ("status", "200")]),
+                        mk_record([("host", "a"), ("ua", "curl"), ("status", "404")]),
+                        mk_record([("host", "a"), ("status", "404")]),
+                        mk_record([("host", "a"), ("status", "404")]),
+                        mk_record([("host", "b"), ("ua", "wget"), ("status", "200")])];
+        let mut rv = Ok(());
+        let s = io::with_str_writer(|wr| rv = write_ltsv_delta(wr, records));
+        assert_eq!(rv, Ok(()));
+        let lines = str::lines(s);
+        assert_eq!(lines.len(), 5);
+        assert_eq!(io::with_str_reader(lines[1], |rd| rd.read_ltsv()),
+                   ~[mk_record([("status", "404"), ("_delta", "")])]);
+        assert_eq!(io::with_str_reader(lines[2], |rd| rd.read_ltsv()),
+                   ~[mk_record([("_delta", "ua")])]);
+        assert_eq!(io::with_str_reader(s, |rd| read_ltsv_delta(rd)), Ok(records));
+        assert!(io::with_str_reader("_delta:\n", |rd| read_ltsv_delta(rd)).is_err());
+        assert_eq!(io::with_str_reader("a:1\tb:2\n_delta:a,,b\n", |rd| read_ltsv_delta(rd)),
+                   Err(~"record 1: invalid label in _delta: \"\""));
+        assert_eq!(io::with_str_reader("a:1\n_delta:a b\n", |rd| read_ltsv_delta(rd)),
+                   Err(~"record 1: invalid label in _delta: \"a b\""));
+    }
 }